@@ -0,0 +1,246 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ChunkOptions, OptionSpec, OptionType, OptionValue};
+
+/// Options of backends that produce plots.
+pub const FIGURE_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "fig-cap",
+        ty: OptionType::List,
+        doc: "Caption of the chunk's figures, as Typst markup; one per figure or shared.",
+    },
+    OptionSpec {
+        name: "fig-alt",
+        ty: OptionType::List,
+        doc: "Alternative text of the chunk's figures; one per figure or shared.",
+    },
+    OptionSpec {
+        name: "fig-width",
+        ty: OptionType::Number,
+        doc: "Width of the plotting device in inches.",
+    },
+    OptionSpec {
+        name: "fig-height",
+        ty: OptionType::Number,
+        doc: "Height of the plotting device in inches.",
+    },
+    OptionSpec {
+        name: "out-width",
+        ty: OptionType::Length,
+        doc: "Width of the figures in the document, as a Typst length such as `50%`.",
+    },
+];
+
+/// How the plots of a chunk are shown in the document.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FigureOptions {
+    pub label: Option<String>,
+    pub caption: Vec<String>,
+    pub alt: Vec<String>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub out_width: Option<String>,
+}
+
+fn texts(values: Option<&[OptionValue]>) -> Vec<String> {
+    values
+        .unwrap_or_default()
+        .iter()
+        .map(|v| match v {
+            OptionValue::String(s) => s.clone(),
+            v => v.to_string(),
+        })
+        .collect()
+}
+
+/// Whether the echoed source of a chunk is shown as a single listing, which
+/// is the case when it is captioned, labelled `lst-...`, numbered or
+/// highlighted.
+pub fn is_listing(options: &ChunkOptions) -> bool {
+    options.get("lst-cap").is_some()
+        || options.bool("code-line-numbers") == Some(true)
+        || options.get("code-highlight").is_some()
+        || options.string("label").is_some_and(is_listing_label)
+}
+
+/// Labels of listings start with `lst-`, as in Quarto; any other label is
+/// given to the chunk's figures.
+pub fn is_listing_label(label: &str) -> bool {
+    label.starts_with("lst-")
+}
+
+impl From<&ChunkOptions> for FigureOptions {
+    fn from(options: &ChunkOptions) -> Self {
+        FigureOptions {
+            label: options
+                .string("label")
+                .filter(|l| !is_listing_label(l))
+                .map(str::to_string),
+            caption: texts(options.list("fig-cap")),
+            alt: texts(options.list("fig-alt")),
+            width: options.number("fig-width"),
+            height: options.number("fig-height"),
+            out_width: options.string("out-width").map(str::to_string),
+        }
+    }
+}
+
+/// Quote `s` as a Typst string literal.
+pub fn typst_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl FigureOptions {
+    /// How the `index`th (from 0) of the `count` plots of a chunk is shown.
+    ///
+    /// When a labelled chunk has several plots, each is labelled
+    /// `<label-n>`. Captions and alternative texts are picked in turn, so a
+    /// single one is shared by all plots.
+    pub fn figure(&self, index: usize, count: usize) -> Figure {
+        let pick = |v: &[String]| (!v.is_empty()).then(|| v[index % v.len()].clone());
+        Figure {
+            label: match &self.label {
+                Some(label) if count > 1 => Some(format!("{}-{}", label, index + 1)),
+                label => label.clone(),
+            },
+            caption: pick(&self.caption),
+            alt: pick(&self.alt),
+            out_width: self.out_width.clone(),
+        }
+    }
+}
+
+/// How one plot is shown in the document.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Figure {
+    pub label: Option<String>,
+    pub caption: Option<String>,
+    pub alt: Option<String>,
+    pub out_width: Option<String>,
+}
+
+impl Figure {
+    /// Typst markup showing an image, given the start of the call that
+    /// loads it without its closing parenthesis, e.g. `image("a.svg"`.
+    ///
+    /// A plot with a caption or label becomes a numbered `#figure`.
+    pub fn render(&self, image: &str) -> String {
+        let mut image = image.to_string();
+        if let Some(width) = &self.out_width {
+            image.push_str(&format!(", width: {}", width));
+        }
+        if let Some(alt) = &self.alt {
+            image.push_str(&format!(", alt: {}", typst_string(alt)));
+        }
+        image.push(')');
+        if self.label.is_none() && self.caption.is_none() {
+            return format!("#{}\n", image);
+        }
+        let mut figure = format!("#figure({}", image);
+        if let Some(caption) = &self.caption {
+            figure.push_str(&format!(", caption: [{}]", caption));
+        }
+        figure.push(')');
+        if let Some(label) = &self.label {
+            figure.push_str(&format!(" <{}>", label));
+        }
+        figure.push('\n');
+        figure
+    }
+}
+
+/// The formats of images a chunk can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphicsType {
+    Png,
+    Svg,
+    Pdf,
+    Jpeg,
+}
+
+impl GraphicsType {
+    /// The format of an image file, from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match ext.as_str() {
+            "png" => GraphicsType::Png,
+            "svg" => GraphicsType::Svg,
+            "pdf" => GraphicsType::Pdf,
+            "jpg" | "jpeg" => GraphicsType::Jpeg,
+            _ => return None,
+        })
+    }
+
+    /// The file extension of the format, which is also its name for
+    /// Typst's `image.decode`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            GraphicsType::Png => "png",
+            GraphicsType::Svg => "svg",
+            GraphicsType::Pdf => "pdf",
+            GraphicsType::Jpeg => "jpg",
+        }
+    }
+}
+
+/// An image produced by a chunk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Graphics {
+    pub ty: GraphicsType,
+    /// The image's bytes, left out when serialized; results that are
+    /// stored or emitted keep images in files of their own.
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    pub figure: Figure,
+}
+
+impl Graphics {
+    /// Read an image a backend saved at `path`, and remove the file, as
+    /// images are written into the document by the driver.
+    pub async fn take_file(path: &Path, figure: Figure) -> std::io::Result<Self> {
+        let ty = GraphicsType::from_path(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown image format: {}", path.display()),
+            )
+        })?;
+        let data = tokio::fs::read(path).await?;
+        tokio::fs::remove_file(path).await.ok();
+        Ok(Graphics { ty, data, figure })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let plain = FigureOptions::default().figure(0, 1);
+        assert_eq!(plain.render("image(\"a.svg\""), "#image(\"a.svg\")\n");
+
+        let figure = FigureOptions {
+            label: Some("fig-iris".to_string()),
+            caption: vec!["The *iris* data".to_string()],
+            alt: vec!["A \"scatter\" plot".to_string()],
+            out_width: Some("50%".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            figure.figure(0, 1).render("image(\"a.svg\""),
+            "#figure(image(\"a.svg\", width: 50%, alt: \"A \\\"scatter\\\" plot\"), caption: [The *iris* data]) <fig-iris>\n"
+        );
+
+        let figures = FigureOptions {
+            label: Some("fig-x".to_string()),
+            caption: vec!["One".to_string(), "Two".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            figures.figure(1, 2).render("image(\"b.svg\""),
+            "#figure(image(\"b.svg\"), caption: [Two]) <fig-x-2>\n"
+        );
+    }
+}
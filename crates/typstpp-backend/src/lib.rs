@@ -1,11 +1,100 @@
-use std::fmt::{Debug, Display};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display},
+    future::Future,
+    time::Duration,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+mod figure;
+mod options;
+mod probe;
+
+pub use figure::{
+    is_listing, is_listing_label, typst_string, Figure, FigureOptions, Graphics, GraphicsType,
+    FIGURE_OPTIONS,
+};
+pub use options::{
+    is_length, parse_duration, split_list, ChunkOptions, OptionError, OptionSpec, OptionType,
+    OptionValue,
+};
+pub use probe::{command_output, find_in_path, BackendInfo};
 
 pub struct Input<'a, O> {
     pub source: &'a str,
     pub options: O,
+    /// Whether this is inline code whose result is spliced into the
+    /// surrounding text instead of being rendered as a block.
+    pub inline: bool,
+    /// The position of this chunk among the chunks of its language in the
+    /// document, stable across runs for naming generated files.
+    pub index: usize,
+    /// How long the chunk may run before it is stopped.
+    pub timeout: Option<Duration>,
+}
+
+/// The error output for a chunk stopped after running for `timeout`.
+pub fn timed_out(timeout: Duration) -> Output<String> {
+    Output {
+        data: format!("chunk timed out after {:?}", timeout),
+        ty: OutputType::Error,
+    }
+}
+
+/// Wait for `run`, a chunk running in an interpreter session, stopping it
+/// after `timeout`.
+///
+/// A chunk that times out yields its error output instead. Its session is
+/// then in an unknown state: dropping it kills the interpreter, and a fresh
+/// one is started for the next chunk.
+pub async fn run_with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    run: F,
+) -> Result<F::Output, Output<String>> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .map_err(|_| timed_out(timeout)),
+        None => Ok(run.await),
+    }
+}
+
+/// A random marker that an interpreter prints after a chunk, so that the
+/// chunk's output can be told apart from the next one's.
+pub fn new_cookie() -> String {
+    let mut rng = rand::thread_rng();
+    let bytes = std::iter::repeat(())
+        .map(|()| rng.sample(rand::distributions::Alphanumeric))
+        .take(16)
+        .collect();
+    String::from_utf8(bytes).unwrap()
 }
 
-#[derive(Debug, Clone)]
+/// Read from `reader` until `cookie` is seen, returning everything before it.
+///
+/// The rest of the line containing the cookie is discarded.
+pub async fn read_until_cookie<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    cookie: &str,
+) -> std::io::Result<String> {
+    let mut buf = String::new();
+    loop {
+        let start = buf.len();
+        if reader.read_line(&mut buf).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("interpreter exited unexpectedly, output: {}", buf),
+            ));
+        }
+        if let Some(pos) = buf[start..].find(cookie) {
+            buf.truncate(start + pos);
+            return Ok(buf);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Output<S: Display> {
     pub data: S,
     pub ty: OutputType,
@@ -20,13 +109,15 @@ where
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum OutputType {
     Typst,
     Code,
     Output,
     Message,
     Error,
+    /// An image; the output's data is unused.
+    Graphics(Graphics),
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -37,13 +128,27 @@ pub enum Error<E: Display> {
 
 #[async_trait::async_trait]
 pub trait Backend {
-    type GlobalOptions;
+    type GlobalOptions: Send + Sync;
     type Options;
     type Output: Display;
     type Error: Display + Debug;
     async fn new<'a>(global_options: Self::GlobalOptions) -> Result<Self, Error<Self::Error>>
     where
         Self: Sized;
+    /// The chunk options this backend understands.
+    fn option_schema() -> &'static [OptionSpec]
+    where
+        Self: Sized;
+    /// Look for the interpreter this backend would run, without starting a
+    /// session.
+    async fn probe(global_options: &Self::GlobalOptions) -> BackendInfo
+    where
+        Self: Sized;
+    /// Whether this backend can run alongside the backends of other
+    /// languages. Backends that cannot are run on their own, one at a time.
+    fn concurrent(&self) -> bool {
+        true
+    }
     async fn compile<'a>(
         &mut self,
         input: Vec<Input<'a, Self::Options>>,
@@ -0,0 +1,566 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    time::Duration,
+};
+
+/// The value of a chunk option, as written after `#| name:`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OptionValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+    List(Vec<OptionValue>),
+    /// An expression in the chunk's language, written as `!expr ...`.
+    Expr(String),
+}
+
+/// The type a chunk option is declared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionType {
+    Bool,
+    Number,
+    /// Any scalar, converted to its text.
+    String,
+    /// A list, or a single value standing for a list of one.
+    List,
+    /// An expression; any value is accepted and left to the backend.
+    Expr,
+    /// A duration such as `30s`, `500ms` or `2m`, or a number of seconds.
+    Duration,
+    /// A Typst length or ratio such as `3in` or `50%`, written into the
+    /// document as it is.
+    Length,
+}
+
+impl Display for OptionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OptionType::Bool => "a boolean",
+            OptionType::Number => "a number",
+            OptionType::String => "a string",
+            OptionType::List => "a list",
+            OptionType::Expr => "an expression",
+            OptionType::Duration => "a duration",
+            OptionType::Length => "a length such as `3in` or `50%`",
+        })
+    }
+}
+
+/// A chunk option a backend understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub ty: OptionType,
+    pub doc: &'static str,
+}
+
+impl OptionSpec {
+    /// The name of a spec standing for every option not declared by name,
+    /// for backends that pass options on to their interpreter.
+    pub const ANY: &'static str = "*";
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum OptionError {
+    #[error("unknown chunk option `{0}`")]
+    Unknown(String),
+    #[error("invalid chunk option name `{0}`")]
+    InvalidName(String),
+    #[error("invalid value for chunk option `{name}`: expected {expected}, found `{found}`")]
+    InvalidType {
+        name: String,
+        expected: OptionType,
+        found: String,
+    },
+    #[error("malformed value for chunk option `{name}`: {message}")]
+    Syntax { name: String, message: String },
+}
+
+impl OptionError {
+    /// The name of the offending option.
+    pub fn name(&self) -> &str {
+        match self {
+            OptionError::Unknown(name) | OptionError::InvalidName(name) => name,
+            OptionError::InvalidType { name, .. } | OptionError::Syntax { name, .. } => name,
+        }
+    }
+}
+
+/// Parse a duration such as `30s`, `500ms`, `2m` or `1h`; a bare number is
+/// a number of seconds.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n = n.parse::<f64>().ok()?;
+    let secs = match unit.trim() {
+        "ms" => n / 1000.0,
+        "" | "s" => n,
+        "m" | "min" => n * 60.0,
+        "h" => n * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Whether `s` is a number followed by a Typst unit, so that it can be
+/// written into the document as a length.
+pub fn is_length(s: &str) -> bool {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    n.parse::<f64>().is_ok() && ["pt", "mm", "cm", "in", "em", "%", "fr"].contains(&unit)
+}
+
+/// Split `s` at top-level commas, outside of quotes and brackets.
+pub fn split_list(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[' | '(' | '{') => depth += 1,
+            (None, ']' | ')' | '}') => depth = depth.saturating_sub(1),
+            (None, ',') if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unquote(s: &str, quote: char) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = s[1..].chars();
+    loop {
+        match chars.next() {
+            None => return Err(format!("unterminated string {}", s)),
+            Some('\\') => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => return Err(format!("unterminated string {}", s)),
+            },
+            Some(c) if c == quote => break,
+            Some(c) => out.push(c),
+        }
+    }
+    if !chars.as_str().trim().is_empty() {
+        return Err(format!("unexpected text after string {}", s));
+    }
+    Ok(out)
+}
+
+impl OptionValue {
+    /// Parse an option value.
+    ///
+    /// `true`/`false`/`yes`/`no` in any case are booleans, numbers are
+    /// numbers, `"..."` and `'...'` are strings with backslash escapes,
+    /// `[a, b]` is a list, `!expr ...` is an expression, and any other text
+    /// is a string.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("missing value".to_string());
+        }
+        if let Some(expr) = s.strip_prefix("!expr ") {
+            return Ok(OptionValue::Expr(expr.trim().to_string()));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "true" | "yes" => return Ok(OptionValue::Bool(true)),
+            "false" | "no" => return Ok(OptionValue::Bool(false)),
+            _ => {}
+        }
+        if s.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c)) {
+            if let Ok(n) = s.parse::<f64>() {
+                return Ok(OptionValue::Number(n));
+            }
+        }
+        match s.chars().next() {
+            Some(q @ ('"' | '\'')) => unquote(s, q).map(OptionValue::String),
+            Some('[') => {
+                let inner = s
+                    .strip_suffix(']')
+                    .ok_or_else(|| format!("unterminated list {}", s))?[1..]
+                    .trim();
+                if inner.is_empty() {
+                    return Ok(OptionValue::List(vec![]));
+                }
+                split_list(inner)
+                    .into_iter()
+                    .map(OptionValue::parse)
+                    .collect::<Result<_, _>>()
+                    .map(OptionValue::List)
+            }
+            _ => Ok(OptionValue::String(s.to_string())),
+        }
+    }
+
+    /// Check the value against a declared type, converting where the type
+    /// allows it.
+    pub fn check(self, name: &str, ty: OptionType) -> Result<Self, OptionError> {
+        match (ty, self) {
+            (OptionType::Bool, v @ OptionValue::Bool(_))
+            | (OptionType::Number, v @ OptionValue::Number(_))
+            | (OptionType::String, v @ OptionValue::String(_))
+            | (OptionType::List, v @ OptionValue::List(_))
+            | (OptionType::Expr, v) => Ok(v),
+            (OptionType::String, OptionValue::Bool(b)) => Ok(OptionValue::String(b.to_string())),
+            (OptionType::String, OptionValue::Number(n)) => Ok(OptionValue::String(n.to_string())),
+            (OptionType::List, v @ (OptionValue::String(_) | OptionValue::Number(_))) => {
+                Ok(OptionValue::List(vec![v]))
+            }
+            (OptionType::Duration, v @ OptionValue::Number(_)) => Ok(v),
+            (OptionType::Duration, OptionValue::String(s)) if parse_duration(&s).is_some() => {
+                Ok(OptionValue::String(s))
+            }
+            (OptionType::Length, OptionValue::String(s)) if is_length(&s) => {
+                Ok(OptionValue::String(s))
+            }
+            (expected, found) => Err(OptionError::InvalidType {
+                name: name.to_string(),
+                expected,
+                found: found.to_string(),
+            }),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            OptionValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            OptionValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OptionValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[OptionValue]> {
+        match self {
+            OptionValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_duration(&self) -> Option<Duration> {
+        match self {
+            OptionValue::Number(n) => Duration::try_from_secs_f64(*n).ok(),
+            OptionValue::String(s) => parse_duration(s),
+            _ => None,
+        }
+    }
+}
+
+impl Display for OptionValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptionValue::Bool(b) => write!(f, "{}", b),
+            OptionValue::Number(n) => write!(f, "{}", n),
+            OptionValue::String(s) => write!(f, "{:?}", s),
+            OptionValue::List(l) => {
+                f.write_str("[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_str("]")
+            }
+            OptionValue::Expr(e) => write!(f, "!expr {}", e),
+        }
+    }
+}
+
+/// Validated options of one chunk.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChunkOptions {
+    values: BTreeMap<String, OptionValue>,
+    /// The options matched by an [`OptionSpec::ANY`] spec rather than by
+    /// name.
+    undeclared: BTreeSet<String>,
+}
+
+/// Whether `name` can be passed on as it is: a letter followed by letters,
+/// digits, `-` and `_`, so that it cannot break out of wherever a backend
+/// writes it.
+fn is_option_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl ChunkOptions {
+    /// Parse `name: value` pairs and check them against `schema`.
+    ///
+    /// Values of [`OptionType::String`] options are never read as booleans or
+    /// numbers. Options not in `schema` are accepted with any value if it has an
+    /// [`OptionSpec::ANY`] spec and their name is plain enough to pass on.
+    pub fn parse<'a, I>(options: I, schema: &[OptionSpec]) -> Result<Self, OptionError>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut parsed = ChunkOptions::default();
+        for (name, value) in options {
+            let spec = match schema.iter().find(|s| s.name == name) {
+                Some(spec) => spec,
+                None => {
+                    let any = schema
+                        .iter()
+                        .find(|s| s.name == OptionSpec::ANY)
+                        .ok_or_else(|| OptionError::Unknown(name.to_string()))?;
+                    if !is_option_name(name) {
+                        return Err(OptionError::InvalidName(name.to_string()));
+                    }
+                    parsed.undeclared.insert(name.to_string());
+                    any
+                }
+            };
+            let value = match OptionValue::parse(value) {
+                // Text options keep `No` or `1.50` as written.
+                Ok(OptionValue::Bool(_) | OptionValue::Number(_))
+                    if spec.ty == OptionType::String =>
+                {
+                    OptionValue::String(value.trim().to_string())
+                }
+                Ok(value) => value,
+                Err(message) => {
+                    return Err(OptionError::Syntax {
+                        name: name.to_string(),
+                        message,
+                    })
+                }
+            };
+            parsed
+                .values
+                .insert(name.to_string(), value.check(name, spec.ty)?);
+        }
+        Ok(parsed)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OptionValue> {
+        self.values.get(name)
+    }
+
+    pub fn bool(&self, name: &str) -> Option<bool> {
+        self.get(name).and_then(OptionValue::as_bool)
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(OptionValue::as_number)
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(OptionValue::as_str)
+    }
+
+    pub fn list(&self, name: &str) -> Option<&[OptionValue]> {
+        self.get(name).and_then(OptionValue::as_list)
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        self.get(name).and_then(OptionValue::as_duration)
+    }
+
+    pub fn insert(&mut self, name: String, value: OptionValue) {
+        self.undeclared.remove(&name);
+        self.values.insert(name, value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &OptionValue)> {
+        self.values.iter()
+    }
+
+    /// The options not declared by name, to be passed on as they are.
+    pub fn undeclared(&self) -> impl Iterator<Item = (&String, &OptionValue)> {
+        self.values
+            .iter()
+            .filter(|(name, _)| self.undeclared.contains(*name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &[OptionSpec] = &[
+        OptionSpec {
+            name: "echo",
+            ty: OptionType::Bool,
+            doc: "",
+        },
+        OptionSpec {
+            name: "fig-width",
+            ty: OptionType::Number,
+            doc: "",
+        },
+        OptionSpec {
+            name: "depends",
+            ty: OptionType::List,
+            doc: "",
+        },
+        OptionSpec {
+            name: "timeout",
+            ty: OptionType::Duration,
+            doc: "",
+        },
+        OptionSpec {
+            name: "fig-cap",
+            ty: OptionType::String,
+            doc: "",
+        },
+        OptionSpec {
+            name: "out-width",
+            ty: OptionType::Length,
+            doc: "",
+        },
+    ];
+
+    #[test]
+    fn test_parse_values() {
+        for (s, v) in [
+            ("FALSE", OptionValue::Bool(false)),
+            ("yes", OptionValue::Bool(true)),
+            ("-1.5", OptionValue::Number(-1.5)),
+            (
+                "\"a \\\"b\\\"\"",
+                OptionValue::String("a \"b\"".to_string()),
+            ),
+            ("'c, d'", OptionValue::String("c, d".to_string())),
+            ("plain text", OptionValue::String("plain text".to_string())),
+            ("!expr c(1, 2)", OptionValue::Expr("c(1, 2)".to_string())),
+            (
+                "[1, \"a, b\", [true]]",
+                OptionValue::List(vec![
+                    OptionValue::Number(1.0),
+                    OptionValue::String("a, b".to_string()),
+                    OptionValue::List(vec![OptionValue::Bool(true)]),
+                ]),
+            ),
+        ] {
+            assert_eq!(OptionValue::parse(s), Ok(v), "{}", s);
+        }
+        assert!(OptionValue::parse("").is_err());
+        assert!(OptionValue::parse("\"open").is_err());
+        assert!(OptionValue::parse("[1, 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_chunk_options() {
+        let options = ChunkOptions::parse(
+            [("echo", "no"), ("fig-width", "4"), ("depends", "data.csv")],
+            SCHEMA,
+        )
+        .unwrap();
+        assert_eq!(options.bool("echo"), Some(false));
+        assert_eq!(options.number("fig-width"), Some(4.0));
+        assert_eq!(
+            options.list("depends"),
+            Some(&[OptionValue::String("data.csv".to_string())][..])
+        );
+
+        for (value, secs) in [("30s", 30.0), ("500ms", 0.5), ("2m", 120.0), ("1.5", 1.5)] {
+            let options = ChunkOptions::parse([("timeout", value)], SCHEMA).unwrap();
+            assert_eq!(
+                options.duration("timeout"),
+                Some(Duration::from_secs_f64(secs))
+            );
+        }
+        assert!(ChunkOptions::parse([("timeout", "soon")], SCHEMA).is_err());
+
+        for value in ["6in", "50%", "2.5cm"] {
+            let options = ChunkOptions::parse([("out-width", value)], SCHEMA).unwrap();
+            assert_eq!(options.string("out-width"), Some(value));
+        }
+        for value in ["50%) #panic(", "6", "wide", "6 in"] {
+            let err = ChunkOptions::parse([("out-width", value)], SCHEMA).unwrap_err();
+            assert!(matches!(err, OptionError::InvalidType { .. }), "{}", value);
+        }
+
+        for value in ["No", "1.50"] {
+            let options = ChunkOptions::parse([("fig-cap", value)], SCHEMA).unwrap();
+            assert_eq!(options.string("fig-cap"), Some(value));
+        }
+
+        let err = ChunkOptions::parse([("echo", "maybe")], SCHEMA).unwrap_err();
+        assert_eq!(err.name(), "echo");
+        assert!(matches!(err, OptionError::InvalidType { .. }));
+        assert_eq!(
+            ChunkOptions::parse([("ecko", "true")], SCHEMA),
+            Err(OptionError::Unknown("ecko".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_undeclared() {
+        let schema = [
+            SCHEMA,
+            &[OptionSpec {
+                name: OptionSpec::ANY,
+                ty: OptionType::Expr,
+                doc: "",
+            }],
+        ]
+        .concat();
+        let options = ChunkOptions::parse(
+            [
+                ("echo", "no"),
+                ("results", "asis"),
+                ("dev-args", "!expr list(bg = 'white')"),
+            ],
+            &schema,
+        )
+        .unwrap();
+        assert_eq!(
+            options.undeclared().collect::<Vec<_>>(),
+            [
+                (
+                    &"dev-args".to_string(),
+                    &OptionValue::Expr("list(bg = 'white')".to_string())
+                ),
+                (
+                    &"results".to_string(),
+                    &OptionValue::String("asis".to_string())
+                ),
+            ]
+        );
+        // Declared options are still checked.
+        assert!(ChunkOptions::parse([("echo", "maybe")], &schema).is_err());
+        for name in ["a b", "x=1, y", "1st", "-x", "x\"y"] {
+            assert_eq!(
+                ChunkOptions::parse([(name, "1")], &schema),
+                Err(OptionError::InvalidName(name.to_string()))
+            );
+        }
+    }
+}
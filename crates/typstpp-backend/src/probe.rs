@@ -0,0 +1,59 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+/// What a backend found out about the interpreter it would run.
+#[derive(Debug, Clone, Default)]
+pub struct BackendInfo {
+    /// Where the interpreter is installed, if it was found.
+    pub path: Option<PathBuf>,
+    /// Versions of the interpreter and of the libraries the backend relies
+    /// on, e.g. `("knitr", "1.45")`.
+    pub versions: Vec<(String, String)>,
+    /// Why the backend cannot run on this machine, if it cannot.
+    pub missing: Option<String>,
+}
+
+impl BackendInfo {
+    pub fn available(&self) -> bool {
+        self.missing.is_none()
+    }
+
+    /// Info for an interpreter that could not be found.
+    pub fn missing<S: Into<String>>(reason: S) -> Self {
+        BackendInfo {
+            missing: Some(reason.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Find `program` the way a shell would: as a path if it contains a
+/// separator, and in `PATH` otherwise.
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    let is_file = |p: &Path| p.is_file();
+    if program.contains(std::path::MAIN_SEPARATOR) || program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_file(&path).then_some(path);
+    }
+    let exts = if cfg!(windows) {
+        vec!["", ".exe", ".cmd", ".bat"]
+    } else {
+        vec![""]
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?).find_map(|dir| {
+        exts.iter()
+            .map(|ext| dir.join(format!("{}{}", program, ext)))
+            .find(|p| is_file(p))
+    })
+}
+
+/// Run `program` and return what it printed to stdout, trimmed, if it
+/// succeeded.
+pub async fn command_output(program: &Path, args: &[&str]) -> Option<String> {
+    let out = Command::new(program).args(args).output().await.ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
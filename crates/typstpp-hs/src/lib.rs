@@ -1,17 +1,129 @@
-use rand::{rngs::ThreadRng, Rng};
+use split::split_statements;
 use std::process::Stdio;
-use tokio::process::Command;
-use typstpp_backend::{Backend, Input};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+};
+use typstpp_backend::{
+    command_output, find_in_path, new_cookie, read_until_cookie, Backend, BackendInfo,
+    ChunkOptions, Input, OptionSpec, OptionType,
+};
+mod split;
 
-pub struct HsBackend;
+pub struct HsBackend {
+    global_options: HsGlobalOptions,
+    session: Option<GhciSession>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HsGlobalOptions {
+    /// The GHCi executable to run, `ghci` from `PATH` by default.
+    pub command: Option<String>,
+    /// Extra arguments passed to GHCi, such as language extensions.
+    pub args: Vec<String>,
+}
+
+impl HsGlobalOptions {
+    fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or("ghci")
+    }
+}
 
 impl HsBackend {
-    pub fn new_cookie(&self, rng: &mut ThreadRng) -> String {
-        let bytes = std::iter::repeat(())
-            .map(|()| rng.sample(rand::distributions::Alphanumeric))
-            .take(16)
-            .collect();
-        String::from_utf8(bytes).unwrap()
+    pub fn new_cookie(&self) -> String {
+        new_cookie()
+    }
+
+    /// The running ghci session, started on first use.
+    async fn session(&mut self) -> Result<&mut GhciSession, Error> {
+        if self.session.is_none() {
+            self.session = Some(GhciSession::spawn(&self.global_options).await?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+}
+
+/// A long-lived `ghci` process driven over its standard streams.
+///
+/// Every command sent is followed by a random cookie printed to both stdout
+/// and stderr, so the output belonging to that command can be told apart
+/// from the next one.
+struct GhciSession {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: BufReader<ChildStderr>,
+}
+
+impl GhciSession {
+    async fn spawn(options: &HsGlobalOptions) -> Result<Self, Error> {
+        let mut child = Command::new(options.command())
+            .arg("-v0")
+            .arg("-ignore-dot-ghci")
+            .args(&options.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                Error::SessionError(format!("failed to start {}: {}", options.command(), e))
+            })?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut session = GhciSession {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        };
+        session
+            .run(":set prompt \"\"\n:set prompt-cont \"\"")
+            .await?;
+        Ok(session)
+    }
+
+    /// Send `code` to ghci and collect what it printed to stdout and stderr.
+    async fn run(&mut self, code: &str) -> Result<(String, String), Error> {
+        let cookie = new_cookie();
+        let mut command = String::from(code);
+        if !command.ends_with('\n') {
+            command.push('\n');
+        }
+        command.push_str(&format!(
+            "putStrLn \"{0}\"\nSystem.IO.hPutStrLn System.IO.stderr \"{0}\"\n",
+            cookie
+        ));
+        self.stdin
+            .write_all(command.as_bytes())
+            .await
+            .map_err(|e| Error::SessionError(format!("{}", e)))?;
+        self.stdin
+            .flush()
+            .await
+            .map_err(|e| Error::SessionError(format!("{}", e)))?;
+        let (stdout, stderr) = tokio::join!(
+            read_until_cookie(&mut self.stdout, &cookie),
+            read_until_cookie(&mut self.stderr, &cookie)
+        );
+        let session_error = |e: std::io::Error| Error::SessionError(format!("{}", e));
+        Ok((
+            stdout.map_err(session_error)?,
+            stderr.map_err(session_error)?,
+        ))
+    }
+
+    async fn quit(mut self) -> Result<(), Error> {
+        self.stdin
+            .write_all(b":quit\n")
+            .await
+            .map_err(|e| Error::SessionError(format!("{}", e)))?;
+        self.child
+            .wait()
+            .await
+            .map_err(|e| Error::SessionError(format!("{}", e)))?;
+        Ok(())
     }
 }
 
@@ -20,41 +132,73 @@ pub struct HsOptions {
     eval: bool,
 }
 
-impl From<std::collections::HashMap<String, String>> for HsOptions {
-    fn from(m: std::collections::HashMap<String, String>) -> Self {
+impl From<ChunkOptions> for HsOptions {
+    fn from(options: ChunkOptions) -> Self {
         HsOptions {
-            echo: m
-                .get("echo")
-                .map(|s| s == "true" || s == "1" || s == "yes")
-                .unwrap_or(true),
-            eval: m
-                .get("eval")
-                .map(|s| s == "true" || s == "1" || s == "yes")
-                .unwrap_or(true),
+            echo: options.bool("echo").unwrap_or(true),
+            eval: options.bool("eval").unwrap_or(true),
         }
     }
 }
 
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "echo",
+        ty: OptionType::Bool,
+        doc: "Show the source of the chunk.",
+    },
+    OptionSpec {
+        name: "eval",
+        ty: OptionType::Bool,
+        doc: "Run the chunk.",
+    },
+];
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Eval error: {0}")]
     EvalError(String),
+    #[error("GHCi session error: {0}")]
+    SessionError(String),
 }
 
 #[async_trait::async_trait]
 impl Backend for HsBackend {
-    type GlobalOptions = ();
+    type GlobalOptions = HsGlobalOptions;
     type Options = HsOptions;
     type Output = String;
     type Error = Error;
 
     async fn new<'a>(
-        _global_options: Self::GlobalOptions,
+        global_options: Self::GlobalOptions,
     ) -> Result<Self, typstpp_backend::Error<Self::Error>>
     where
         Self: Sized,
     {
-        Ok(HsBackend)
+        Ok(HsBackend {
+            global_options,
+            session: None,
+        })
+    }
+
+    fn option_schema() -> &'static [OptionSpec] {
+        OPTIONS
+    }
+
+    async fn probe(global_options: &Self::GlobalOptions) -> BackendInfo {
+        let Some(path) = find_in_path(global_options.command()) else {
+            return BackendInfo::missing(format!("{} not found", global_options.command()));
+        };
+        let versions = command_output(&path, &["--numeric-version"])
+            .await
+            .map(|v| ("GHC".to_string(), v))
+            .into_iter()
+            .collect();
+        BackendInfo {
+            path: Some(path),
+            versions,
+            missing: None,
+        }
     }
 
     async fn compile<'a>(
@@ -62,91 +206,201 @@ impl Backend for HsBackend {
         input: Vec<Input<'a, Self::Options>>,
     ) -> Result<Vec<Vec<typstpp_backend::Output<Self::Output>>>, typstpp_backend::Error<Self::Error>>
     {
-        let cookies = (0..input.len())
-            .map(|_| self.new_cookie(&mut rand::thread_rng()))
-            .collect::<Vec<_>>();
-        let mut child = Command::new("ghc");
-        child
-            .stdout(Stdio::piped())
-            .stdin(Stdio::null())
-            .stderr(Stdio::piped());
-        for (i, input) in input.iter().enumerate() {
-            if input.options.eval {
-                for line in input.source.lines() {
-                    child.arg("-e").arg(line);
-                }
-            }
-            child.arg("-e").arg(format!("putStrLn \"{}\"", cookies[i]));
-        }
-        let output = child.output().await.map_err(|e| {
-            typstpp_backend::Error::BackendError(Error::EvalError(format!("{}", e)))
-        })?;
-        let mut stdout = String::from_utf8(output.stdout).map_err(|e| {
-            typstpp_backend::Error::BackendError(Error::EvalError(format!("{}", e)))
-        })?;
-        let stderr = String::from_utf8(output.stderr).map_err(|e| {
-            typstpp_backend::Error::BackendError(Error::EvalError(format!("{}", e)))
-        })?;
         let mut outputs = vec![];
-        for (i, cookie) in cookies.iter().enumerate() {
-            let breakpoint = stdout
-                .find(cookie)
-                .ok_or(typstpp_backend::Error::BackendError(Error::EvalError(
-                    format!("Cookie not found, stderr: {}", stderr),
-                )))?;
+        for input in input {
             let mut chunk_output = vec![];
-            if input[0].options.echo {
+            if input.options.echo && !input.inline {
                 chunk_output.push(typstpp_backend::Output {
-                    data: input[i].source.to_string(),
+                    data: input.source.to_string(),
                     ty: typstpp_backend::OutputType::Code,
                 });
             }
-            if !stderr.is_empty() {
-                chunk_output.push(typstpp_backend::Output {
-                    data: stderr.clone(),
-                    ty: typstpp_backend::OutputType::Error,
-                });
+            if input.options.eval {
+                let code = split_statements(input.source)
+                    .iter()
+                    .map(|s| s.to_ghci())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let run = self
+                    .session()
+                    .await
+                    .map_err(typstpp_backend::Error::BackendError)?
+                    .run(&code);
+                let result = match typstpp_backend::run_with_timeout(input.timeout, run).await {
+                    Ok(result) => result,
+                    Err(timed_out) => {
+                        self.session = None;
+                        chunk_output.push(timed_out);
+                        outputs.push(chunk_output);
+                        continue;
+                    }
+                };
+                let (stdout, stderr) = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        // GHCi exited or fell out of step with the cookies;
+                        // a fresh session is started for the next chunk.
+                        self.session = None;
+                        return Err(typstpp_backend::Error::BackendError(e));
+                    }
+                };
+                if !stderr.is_empty() {
+                    let ty = if is_warning(&stderr) {
+                        typstpp_backend::OutputType::Message
+                    } else {
+                        typstpp_backend::OutputType::Error
+                    };
+                    chunk_output.push(typstpp_backend::Output { data: stderr, ty });
+                }
+                if input.inline {
+                    chunk_output.push(typstpp_backend::Output {
+                        data: stdout.trim_end().to_string(),
+                        ty: typstpp_backend::OutputType::Typst,
+                    });
+                } else if !stdout.is_empty() {
+                    chunk_output.push(typstpp_backend::Output {
+                        data: stdout,
+                        ty: typstpp_backend::OutputType::Output,
+                    });
+                }
             }
-            chunk_output.push(typstpp_backend::Output {
-                data: stdout.drain(0..breakpoint).collect(),
-                ty: typstpp_backend::OutputType::Output,
-            });
-            stdout.drain(0..cookie.len());
             outputs.push(chunk_output);
         }
         Ok(outputs)
     }
 
     async fn reset(&mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
+        // `:load` without arguments unloads all modules and drops every
+        // binding made at the prompt.
+        if let Some(session) = self.session.as_mut() {
+            if let Err(e) = session.run(":load").await {
+                self.session = None;
+                return Err(typstpp_backend::Error::BackendError(e));
+            }
+        }
         Ok(())
     }
 
     async fn close(mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
-        Ok(())
+        match self.session {
+            Some(session) => session
+                .quit()
+                .await
+                .map_err(typstpp_backend::Error::BackendError),
+            None => Ok(()),
+        }
     }
 }
 
+/// Whether GHCi's `stderr` holds nothing but warnings about the input: its
+/// diagnostics, such as `<interactive>:1:1: warning: ...`, are all
+/// warnings, and no exception was thrown.
+fn is_warning(stderr: &str) -> bool {
+    stderr.trim_start().starts_with("<interactive>:")
+        && !stderr.lines().any(|l| l.starts_with("*** Exception"))
+        && stderr
+            .lines()
+            .filter(|l| l.starts_with("<interactive>:"))
+            .all(|l| l.contains(": warning:"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_warning() {
+        let warning =
+            "<interactive>:1:5: warning: [GHC-18042] [-Wtype-defaults]\n    \u{2022} Defaulting\n";
+        assert!(is_warning(warning));
+        assert!(!is_warning(
+            "<interactive>:1:1: error: [GHC-88464]\n    Variable not in scope: x\n"
+        ));
+        assert!(!is_warning(&format!("{}*** Exception: boom\n", warning)));
+        assert!(!is_warning("warning threshold exceeded\n"));
+    }
+
     #[tokio::test]
     async fn test_hs_backend() {
-        let mut backend = HsBackend::new(()).await.unwrap();
+        let mut backend = HsBackend::new(HsGlobalOptions::default()).await.unwrap();
         let input = vec![Input {
             source: "putStrLn \"Hello, world!\"",
             options: HsOptions {
                 echo: true,
                 eval: true,
             },
+            inline: false,
+            index: 0,
+            timeout: None,
         }];
         let outputs = backend.compile(input).await.unwrap();
         assert_eq!(
             outputs,
-            vec![vec![typstpp_backend::Output {
-                data: "Hello, world!\n".to_string(),
+            vec![vec![
+                typstpp_backend::Output {
+                    data: "putStrLn \"Hello, world!\"".to_string(),
+                    ty: typstpp_backend::OutputType::Code
+                },
+                typstpp_backend::Output {
+                    data: "Hello, world!\n".to_string(),
+                    ty: typstpp_backend::OutputType::Output
+                }
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hs_errors_stay_in_chunk() {
+        let mut backend = HsBackend::new(HsGlobalOptions::default()).await.unwrap();
+        let options = || HsOptions {
+            echo: false,
+            eval: true,
+        };
+        let input = vec![
+            Input {
+                source: "let x = 1 :: Int",
+                options: options(),
+                inline: false,
+                index: 0,
+                timeout: None,
+            },
+            Input {
+                source: "undefinedName",
+                options: options(),
+                inline: false,
+                index: 0,
+                timeout: None,
+            },
+            Input {
+                source: "print (x + 1)",
+                options: options(),
+                inline: false,
+                index: 0,
+                timeout: None,
+            },
+        ];
+        let outputs = backend.compile(input).await.unwrap();
+        assert!(outputs[0].is_empty());
+        assert_eq!(outputs[1][0].ty, typstpp_backend::OutputType::Error);
+        assert_eq!(
+            outputs[2],
+            vec![typstpp_backend::Output {
+                data: "2\n".to_string(),
                 ty: typstpp_backend::OutputType::Output
-            }]]
+            }]
         );
+
+        backend.reset().await.unwrap();
+        let outputs = backend
+            .compile(vec![Input {
+                source: "print x",
+                options: options(),
+                inline: false,
+                index: 0,
+                timeout: None,
+            }])
+            .await
+            .unwrap();
+        assert_eq!(outputs[0][0].ty, typstpp_backend::OutputType::Error);
     }
 }
@@ -0,0 +1,303 @@
+/// A top-level unit of a Haskell chunk, as it should be sent to GHCi.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// A declaration: a type signature with its equations, or a
+    /// data/class/instance block.
+    Declaration(String),
+    /// An expression or a statement to evaluate, like `x <- getLine`.
+    Expression(String),
+    /// A GHCi command or an import, passed through unchanged.
+    Command(String),
+}
+
+impl Statement {
+    /// Render the statement as GHCi input, wrapping anything that spans
+    /// several lines in a `:{`/`:}` block.
+    pub fn to_ghci(&self) -> String {
+        match self {
+            Statement::Declaration(s) => format!(":{{\n{}\n:}}", s),
+            Statement::Expression(s) if s.contains('\n') => format!(":{{\n{}\n:}}", s),
+            Statement::Expression(s) | Statement::Command(s) => s.clone(),
+        }
+    }
+}
+
+const DECLARATION_KEYWORDS: &[&str] = &[
+    "data", "newtype", "type", "class", "instance", "deriving", "default", "infix", "infixl",
+    "infixr", "foreign",
+];
+
+fn is_symbol(c: char) -> bool {
+    "!#$%&*+./<=>?@\\^|-~:".contains(c)
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '\''
+}
+
+/// Tokens of a single line, skipping string and character literals.
+///
+/// Each token is paired with the bracket depth it was found at.
+fn tokens(line: &str) -> Vec<(String, usize)> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    let mut chars = line.chars().peekable();
+    let mut prev_ident = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut escaped = false;
+                for c in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                tokens.push(("\"\"".to_string(), depth));
+                prev_ident = false;
+            }
+            '\'' if !prev_ident => {
+                let mut escaped = false;
+                for c in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '\'' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                tokens.push(("''".to_string(), depth));
+                prev_ident = false;
+            }
+            '(' | '[' | '{' => {
+                tokens.push((c.to_string(), depth));
+                depth += 1;
+                prev_ident = false;
+            }
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                tokens.push((c.to_string(), depth));
+                prev_ident = false;
+            }
+            '`' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '`').collect();
+                tokens.push((format!("`{}`", name), depth));
+                prev_ident = false;
+            }
+            c if is_symbol(c) => {
+                let mut op = c.to_string();
+                while let Some(&c) = chars.peek().filter(|c| is_symbol(**c)) {
+                    op.push(c);
+                    chars.next();
+                }
+                if op.starts_with("--") && op.chars().all(|c| c == '-') {
+                    break;
+                }
+                tokens.push((op, depth));
+                prev_ident = false;
+            }
+            c if is_ident(c) => {
+                let mut ident = c.to_string();
+                while let Some(&c) = chars.peek().filter(|c| is_ident(**c)) {
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push((ident, depth));
+                prev_ident = true;
+            }
+            _ => prev_ident = false,
+        }
+    }
+    tokens
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    /// A declaration of the given name, merged with neighbouring
+    /// declarations of the same name.
+    Named(String),
+    /// A declaration that stands on its own.
+    Declaration,
+    Expression,
+    Command,
+}
+
+fn classify(first_line: &str) -> Kind {
+    let trimmed = first_line.trim_start();
+    if trimmed.starts_with(':') || trimmed.starts_with("import ") {
+        return Kind::Command;
+    }
+    let tokens = tokens(trimmed);
+    let Some((head, _)) = tokens.first() else {
+        return Kind::Expression;
+    };
+    if DECLARATION_KEYWORDS.contains(&head.as_str()) {
+        return Kind::Declaration;
+    }
+    if head == "let" || head == "do" {
+        return Kind::Expression;
+    }
+    let Some(pos) = tokens
+        .iter()
+        .position(|(t, depth)| *depth == 0 && ["=", "::", "<-"].contains(&t.as_str()))
+    else {
+        return Kind::Expression;
+    };
+    if tokens[pos].0 == "<-" {
+        return Kind::Expression;
+    }
+    // Infix definitions like ``x <+> y = ...`` or ``a `f` b = ...`` name the
+    // operator rather than the first argument.
+    let name = match tokens.get(1) {
+        Some((op, 0)) if pos > 1 && (op.starts_with('`') || op.chars().all(is_symbol)) => {
+            op.trim_matches('`').to_string()
+        }
+        _ if head == "(" => {
+            tokens
+                .iter()
+                .take_while(|(t, _)| t != ")")
+                .map(|(t, _)| t.as_str())
+                .collect::<String>()
+                + ")"
+        }
+        _ => head.clone(),
+    };
+    Kind::Named(name)
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Whether a line is empty or holds only a line comment.
+fn is_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty()
+        || (trimmed.starts_with("--")
+            && !trimmed
+                .trim_start_matches('-')
+                .starts_with(|c: char| is_symbol(c)))
+}
+
+/// Split a Haskell chunk into top-level declarations and expressions.
+///
+/// Lines indented deeper than the chunk's own indentation continue the
+/// previous line, a type signature is grouped with the equations that
+/// follow it, and explicit `:{`/`:}` blocks are kept as they are.
+pub fn split_statements(source: &str) -> Vec<Statement> {
+    let lines = source
+        .lines()
+        .map(|l| l.strip_suffix('\r').unwrap_or(l))
+        .collect::<Vec<_>>();
+    let base = lines
+        .iter()
+        .filter(|l| !is_blank(l) && ![":{", ":}"].contains(&l.trim()))
+        .map(|l| indent_of(l))
+        .min()
+        .unwrap_or(0);
+
+    let mut groups: Vec<(Kind, Vec<String>)> = Vec::new();
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        if line.trim() == ":{" {
+            let mut block = vec![line.to_string()];
+            for line in lines.by_ref() {
+                block.push(line.to_string());
+                if line.trim() == ":}" {
+                    break;
+                }
+            }
+            groups.push((Kind::Command, block));
+            continue;
+        }
+        let dedented = line.get(base..).unwrap_or(line.trim_start()).to_string();
+        let continues = is_blank(line) || indent_of(line) > base;
+        match groups.last_mut() {
+            Some((kind, group)) if continues && *kind != Kind::Command => group.push(dedented),
+            _ if is_blank(line) => {}
+            _ => groups.push((classify(line), vec![dedented])),
+        }
+    }
+
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut last_name: Option<String> = None;
+    for (kind, mut group) in groups {
+        while group.last().is_some_and(|l| l.trim().is_empty()) {
+            group.pop();
+        }
+        let text = group.join("\n");
+        let name = match &kind {
+            Kind::Named(name) => Some(name.clone()),
+            _ => None,
+        };
+        match (kind, statements.last_mut()) {
+            (Kind::Named(_), Some(Statement::Declaration(prev))) if name == last_name => {
+                prev.push('\n');
+                prev.push_str(&text);
+            }
+            (Kind::Named(_) | Kind::Declaration, _) => {
+                statements.push(Statement::Declaration(text))
+            }
+            (Kind::Expression, _) => statements.push(Statement::Expression(text)),
+            (Kind::Command, _) => statements.push(Statement::Command(text)),
+        }
+        last_name = name;
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_function() {
+        let source = "fib :: Int -> Int\nfib 0 = 0\nfib 1 = 1\nfib n = fib (n-1) + fib (n-2)\n\nmap fib [0..10]\n";
+        assert_eq!(
+            split_statements(source),
+            vec![
+                Statement::Declaration(
+                    "fib :: Int -> Int\nfib 0 = 0\nfib 1 = 1\nfib n = fib (n-1) + fib (n-2)"
+                        .to_string()
+                ),
+                Statement::Expression("map fib [0..10]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_blocks() {
+        let source = "  data Shape = Circle Double\n    | Square Double\n    deriving Show\n\n  area :: Shape -> Double\n  area (Circle r) = pi * r * r\n  area (Square a) = a * a\n  x <+> y = x + y\n  main = do\n    print 1\n\n    print 2\n  import Data.List\n  x <- return 1\n  area (Circle 1) == 1\n";
+        assert_eq!(
+            split_statements(source),
+            vec![
+                Statement::Declaration(
+                    "data Shape = Circle Double\n  | Square Double\n  deriving Show".to_string()
+                ),
+                Statement::Declaration(
+                    "area :: Shape -> Double\narea (Circle r) = pi * r * r\narea (Square a) = a * a"
+                        .to_string()
+                ),
+                Statement::Declaration("x <+> y = x + y".to_string()),
+                Statement::Declaration("main = do\n  print 1\n\n  print 2".to_string()),
+                Statement::Command("import Data.List".to_string()),
+                Statement::Expression("x <- return 1".to_string()),
+                Statement::Expression("area (Circle 1) == 1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_keeps_explicit_blocks() {
+        let source = ":{\n  f :: Int\n  f = 1\n:}\n\nprint f\n";
+        let statements = split_statements(source);
+        assert_eq!(
+            statements,
+            vec![
+                Statement::Command(":{\n  f :: Int\n  f = 1\n:}".to_string()),
+                Statement::Expression("print f".to_string()),
+            ]
+        );
+        assert_eq!(statements[0].to_ghci(), ":{\n  f :: Int\n  f = 1\n:}");
+    }
+}
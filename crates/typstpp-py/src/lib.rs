@@ -0,0 +1,508 @@
+use std::{path::Path, process::Stdio, sync::OnceLock};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+use typstpp_backend::{
+    command_output, find_in_path, new_cookie, Backend, BackendInfo, ChunkOptions, FigureOptions,
+    Graphics, Input, OptionSpec, OptionType, FIGURE_OPTIONS,
+};
+
+pub struct PyBackend {
+    global_options: PyGlobalOptions,
+    session: Option<PySession>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PyGlobalOptions {
+    pub figure_path_prefix: Option<String>,
+    /// The Python interpreter to run, `python3` from `PATH` by default.
+    pub command: Option<String>,
+    /// Extra arguments passed to the interpreter.
+    pub args: Vec<String>,
+}
+
+impl PyGlobalOptions {
+    fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or("python3")
+    }
+}
+
+pub struct PyOptions {
+    echo: bool,
+    eval: bool,
+    figure: FigureOptions,
+}
+
+impl From<ChunkOptions> for PyOptions {
+    fn from(options: ChunkOptions) -> Self {
+        PyOptions {
+            echo: options.bool("echo").unwrap_or(true),
+            eval: options.bool("eval").unwrap_or(true),
+            figure: FigureOptions::from(&options),
+        }
+    }
+}
+
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "echo",
+        ty: OptionType::Bool,
+        doc: "Show the source of the chunk.",
+    },
+    OptionSpec {
+        name: "eval",
+        ty: OptionType::Bool,
+        doc: "Run the chunk.",
+    },
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Python session error: {0}")]
+    SessionError(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::SessionError(format!("{}", e))
+    }
+}
+
+/// One piece of what a chunk produced, as reported by `prelude.py`.
+#[derive(Debug, PartialEq)]
+enum Record {
+    Stdout(String),
+    Stderr(String),
+    Error(String),
+    Value(String),
+    Figure(String),
+    End,
+}
+
+/// A long-lived `python3` process running `prelude.py`.
+///
+/// Each chunk is sent as a header line `<cookie> <length> <inline> <figure
+/// width> <figure height> <figure prefix>`, with `-` for a default size,
+/// followed by the source, and answered with a sequence of
+/// length-prefixed records tagged with the same cookie. A header line
+/// `<cookie> reset` clears the session's variables instead.
+struct PySession {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl PySession {
+    async fn spawn(options: &PyGlobalOptions) -> Result<Self, Error> {
+        let mut child = Command::new(options.command())
+            .args(&options.args)
+            .arg("-u")
+            .arg("-c")
+            .arg(include_str!("prelude.py"))
+            .env("MPLBACKEND", "Agg")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                Error::SessionError(format!("failed to start {}: {}", options.command(), e))
+            })?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(PySession {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    async fn read_record(&mut self, cookie: &str) -> Result<Record, Error> {
+        let mut header = String::new();
+        if self.stdout.read_line(&mut header).await? == 0 {
+            return Err(Error::SessionError(
+                "python3 exited unexpectedly".to_string(),
+            ));
+        }
+        let mut parts = header.trim_end().splitn(3, ' ');
+        let (Some(c), Some(kind), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(Error::SessionError(format!("malformed record: {}", header)));
+        };
+        if c != cookie {
+            return Err(Error::SessionError(format!("Cookie mismatch: {}", header)));
+        }
+        let len = len
+            .parse::<usize>()
+            .map_err(|_| Error::SessionError(format!("malformed record: {}", header)))?;
+        let mut data = vec![0; len];
+        self.stdout.read_exact(&mut data).await?;
+        let data = String::from_utf8(data).map_err(|e| Error::SessionError(format!("{}", e)))?;
+        Ok(match kind {
+            "stdout" => Record::Stdout(data),
+            "stderr" => Record::Stderr(data),
+            "error" => Record::Error(data),
+            "value" => Record::Value(data),
+            "figure" => Record::Figure(data),
+            "end" => Record::End,
+            _ => {
+                return Err(Error::SessionError(format!(
+                    "unknown record kind: {}",
+                    kind
+                )))
+            }
+        })
+    }
+
+    async fn run(
+        &mut self,
+        source: &str,
+        inline: bool,
+        figure: &FigureOptions,
+        figure_prefix: &str,
+    ) -> Result<Vec<Record>, Error> {
+        let cookie = new_cookie();
+        let size = |s: Option<f64>| s.map_or("-".to_string(), |s| s.to_string());
+        let header = format!(
+            "{} {} {} {} {} {}\n",
+            cookie,
+            source.len(),
+            if inline { 1 } else { 0 },
+            size(figure.width),
+            size(figure.height),
+            figure_prefix
+        );
+        self.stdin.write_all(header.as_bytes()).await?;
+        self.stdin.write_all(source.as_bytes()).await?;
+        self.stdin.flush().await?;
+        let mut records = Vec::new();
+        loop {
+            match self.read_record(&cookie).await? {
+                Record::End => return Ok(records),
+                r => records.push(r),
+            }
+        }
+    }
+
+    /// Forget the variables the chunks defined, keeping the interpreter.
+    async fn reset(&mut self) -> Result<(), Error> {
+        let cookie = new_cookie();
+        self.stdin
+            .write_all(format!("{} reset\n", cookie).as_bytes())
+            .await?;
+        self.stdin.flush().await?;
+        loop {
+            if self.read_record(&cookie).await? == Record::End {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn quit(mut self) -> Result<(), Error> {
+        drop(self.stdin);
+        self.child.wait().await?;
+        Ok(())
+    }
+}
+
+impl PyBackend {
+    /// The running Python session, started on first use.
+    async fn session(&mut self) -> Result<&mut PySession, Error> {
+        if self.session.is_none() {
+            self.session = Some(PySession::spawn(&self.global_options).await?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for PyBackend {
+    type GlobalOptions = PyGlobalOptions;
+    type Options = PyOptions;
+    type Output = String;
+    type Error = Error;
+
+    async fn new<'a>(
+        global_options: Self::GlobalOptions,
+    ) -> Result<Self, typstpp_backend::Error<Self::Error>>
+    where
+        Self: Sized,
+    {
+        Ok(PyBackend {
+            global_options,
+            session: None,
+        })
+    }
+
+    fn option_schema() -> &'static [OptionSpec] {
+        static SCHEMA: OnceLock<Vec<OptionSpec>> = OnceLock::new();
+        SCHEMA.get_or_init(|| [OPTIONS, FIGURE_OPTIONS].concat())
+    }
+
+    async fn probe(global_options: &Self::GlobalOptions) -> BackendInfo {
+        let Some(path) = find_in_path(global_options.command()) else {
+            return BackendInfo::missing(format!("{} not found", global_options.command()));
+        };
+        let mut versions = vec![];
+        if let Some(v) = command_output(&path, &["--version"]).await {
+            versions.push((
+                "Python".to_string(),
+                v.trim_start_matches("Python ").to_string(),
+            ));
+        }
+        // Figures are only captured when matplotlib is installed.
+        if let Some(v) = command_output(
+            &path,
+            &["-c", "import matplotlib; print(matplotlib.__version__)"],
+        )
+        .await
+        {
+            versions.push(("matplotlib".to_string(), v));
+        }
+        BackendInfo {
+            path: Some(path),
+            versions,
+            missing: None,
+        }
+    }
+
+    async fn compile<'a>(
+        &mut self,
+        input: Vec<Input<'a, Self::Options>>,
+    ) -> Result<Vec<Vec<typstpp_backend::Output<Self::Output>>>, typstpp_backend::Error<Self::Error>>
+    {
+        let figure_dir = self
+            .global_options
+            .figure_path_prefix
+            .as_ref()
+            .map(|s| s.as_str().strip_suffix('/').unwrap_or(s))
+            .unwrap_or("figures")
+            .to_string();
+        let mut outputs = vec![];
+        for input in input {
+            let mut chunk_output = vec![];
+            if input.options.echo && !input.inline {
+                chunk_output.push(typstpp_backend::Output {
+                    data: input.source.to_string(),
+                    ty: typstpp_backend::OutputType::Code,
+                });
+            }
+            if input.options.eval {
+                let figure_prefix = format!("{}/typstpp-py-chunk-{}", figure_dir, input.index);
+                let run = self
+                    .session()
+                    .await
+                    .map_err(typstpp_backend::Error::BackendError)?
+                    .run(
+                        input.source,
+                        input.inline,
+                        &input.options.figure,
+                        &figure_prefix,
+                    );
+                let result = match typstpp_backend::run_with_timeout(input.timeout, run).await {
+                    Ok(result) => result,
+                    Err(timed_out) => {
+                        self.session = None;
+                        chunk_output.push(timed_out);
+                        outputs.push(chunk_output);
+                        continue;
+                    }
+                };
+                let records = match result {
+                    Ok(records) => records,
+                    Err(e) => {
+                        // Python exited or fell out of step with the
+                        // protocol; a fresh session is started for the next
+                        // chunk.
+                        self.session = None;
+                        return Err(typstpp_backend::Error::BackendError(e));
+                    }
+                };
+                let figures = records
+                    .iter()
+                    .filter(|r| matches!(r, Record::Figure(_)))
+                    .count();
+                let mut figure = 0;
+                for record in records {
+                    let (data, ty) = match record {
+                        Record::Stdout(s) if !s.is_empty() && !input.inline => {
+                            (s, typstpp_backend::OutputType::Output)
+                        }
+                        Record::Stderr(s) if !s.is_empty() => {
+                            (s, typstpp_backend::OutputType::Message)
+                        }
+                        Record::Error(s) => (s, typstpp_backend::OutputType::Error),
+                        Record::Value(s) => (s, typstpp_backend::OutputType::Typst),
+                        Record::Figure(path) => {
+                            figure += 1;
+                            let graphics = Graphics::take_file(
+                                Path::new(&path),
+                                input.options.figure.figure(figure - 1, figures),
+                            )
+                            .await
+                            .map_err(|e| typstpp_backend::Error::BackendError(e.into()))?;
+                            (
+                                String::new(),
+                                typstpp_backend::OutputType::Graphics(graphics),
+                            )
+                        }
+                        _ => continue,
+                    };
+                    chunk_output.push(typstpp_backend::Output { data, ty });
+                }
+            }
+            outputs.push(chunk_output);
+        }
+        Ok(outputs)
+    }
+
+    async fn reset(&mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
+        // The interpreter stays up, so that a rebuild does not pay for
+        // starting it and importing libraries again.
+        if let Some(session) = self.session.as_mut() {
+            if let Err(e) = session.reset().await {
+                self.session = None;
+                return Err(typstpp_backend::Error::BackendError(e));
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
+        if let Some(session) = self.session.take() {
+            session
+                .quit()
+                .await
+                .map_err(typstpp_backend::Error::BackendError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(echo: bool) -> PyOptions {
+        PyOptions {
+            echo,
+            eval: true,
+            figure: FigureOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_py_backend() {
+        let mut backend = PyBackend::new(PyGlobalOptions::default()).await.unwrap();
+        let outputs = backend
+            .compile(vec![
+                Input {
+                    source: "x = 40\nprint('hello')",
+                    options: options(true),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+                Input {
+                    source: "x + 2",
+                    options: options(false),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+                Input {
+                    source: "x + 2",
+                    options: options(false),
+                    inline: true,
+                    index: 0,
+                    timeout: None,
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                vec![
+                    typstpp_backend::Output {
+                        data: "x = 40\nprint('hello')".to_string(),
+                        ty: typstpp_backend::OutputType::Code,
+                    },
+                    typstpp_backend::Output {
+                        data: "hello\n".to_string(),
+                        ty: typstpp_backend::OutputType::Output,
+                    }
+                ],
+                vec![typstpp_backend::Output {
+                    data: "42\n".to_string(),
+                    ty: typstpp_backend::OutputType::Output,
+                }],
+                vec![typstpp_backend::Output {
+                    data: "42".to_string(),
+                    ty: typstpp_backend::OutputType::Typst,
+                }],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_py_errors() {
+        let mut backend = PyBackend::new(PyGlobalOptions::default()).await.unwrap();
+        let outputs = backend
+            .compile(vec![
+                Input {
+                    source: "import sys\nprint('warn', file=sys.stderr)\n1 / 0",
+                    options: options(false),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+                Input {
+                    source: "print('still alive')",
+                    options: options(false),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(outputs[0][0].ty, typstpp_backend::OutputType::Message);
+        assert_eq!(outputs[0][1].ty, typstpp_backend::OutputType::Error);
+        assert!(outputs[0][1].data.contains("ZeroDivisionError"));
+        assert!(!outputs[0][1].data.contains("prelude"));
+        assert_eq!(outputs[1][0].data, "still alive\n");
+
+        backend.reset().await.unwrap();
+        let outputs = backend
+            .compile(vec![Input {
+                source: "sys",
+                options: options(false),
+                inline: false,
+                index: 0,
+                timeout: None,
+            }])
+            .await
+            .unwrap();
+        assert!(outputs[0][0].data.contains("NameError"));
+    }
+
+    #[tokio::test]
+    async fn test_py_reset_keeps_session() {
+        let mut backend = PyBackend::new(PyGlobalOptions::default()).await.unwrap();
+        let input = |source| Input {
+            source,
+            options: options(false),
+            inline: false,
+            index: 0,
+            timeout: None,
+        };
+        backend.compile(vec![input("x = 1")]).await.unwrap();
+        let pid = backend.session.as_ref().unwrap().child.id();
+
+        backend.reset().await.unwrap();
+        let outputs = backend.compile(vec![input("x")]).await.unwrap();
+        assert!(outputs[0][0].data.contains("NameError"));
+        assert_eq!(backend.session.as_ref().unwrap().child.id(), pid);
+    }
+}
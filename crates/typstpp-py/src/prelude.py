@@ -0,0 +1,106 @@
+import ast
+import linecache
+import os
+import sys
+import traceback
+from io import StringIO
+
+# Keep a private handle on the real stdout for the protocol, and send
+# anything written straight to file descriptor 1 (e.g. by subprocesses)
+# to stderr instead, so it cannot corrupt the stream.
+_proto = os.fdopen(os.dup(1), "wb")
+os.dup2(2, 1)
+_stdin = sys.stdin.buffer
+
+_globals = {"__name__": "__main__", "__builtins__": __builtins__}
+_initial = dict(_globals)
+
+
+def _send(cookie, kind, data):
+    data = data.encode("utf-8")
+    _proto.write(f"{cookie} {kind} {len(data)}\n".encode("utf-8"))
+    _proto.write(data)
+    _proto.flush()
+
+
+def _run(source, inline):
+    linecache.cache["<chunk>"] = (len(source), None, source.splitlines(True), "<chunk>")
+    tree = ast.parse(source, "<chunk>", "eval" if inline else "exec")
+    if inline:
+        return str(eval(compile(tree, "<chunk>", "eval"), _globals))
+    last = None
+    if tree.body and isinstance(tree.body[-1], ast.Expr):
+        last = ast.Expression(tree.body.pop().value)
+    exec(compile(tree, "<chunk>", "exec"), _globals)
+    if last is not None:
+        value = eval(compile(last, "<chunk>", "eval"), _globals)
+        if value is not None:
+            print(repr(value))
+    return None
+
+
+def _save_figures(cookie, prefix, width, height):
+    plt = sys.modules.get("matplotlib.pyplot")
+    if plt is None:
+        return
+    for n, num in enumerate(plt.get_fignums(), start=1):
+        path = f"{prefix}-{n}.svg"
+        os.makedirs(os.path.dirname(path) or ".", exist_ok=True)
+        fig = plt.figure(num)
+        if width != "-" or height != "-":
+            w, h = fig.get_size_inches()
+            fig.set_size_inches(
+                float(width) if width != "-" else w,
+                float(height) if height != "-" else h,
+            )
+        fig.savefig(path)
+        _send(cookie, "figure", path)
+    plt.close("all")
+
+
+def _reset():
+    # Forget what the chunks defined, but keep the modules they imported
+    # loaded, so that importing them again is cheap.
+    _globals.clear()
+    _globals.update(_initial)
+    plt = sys.modules.get("matplotlib.pyplot")
+    if plt is not None:
+        plt.close("all")
+
+
+def _main():
+    while True:
+        header = _stdin.readline()
+        if not header:
+            return
+        fields = header.decode("utf-8").rstrip("\n").split(" ", 5)
+        if fields[1:] == ["reset"]:
+            _reset()
+            _send(fields[0], "end", "")
+            continue
+        cookie, length, inline, width, height, prefix = fields
+        source = _stdin.read(int(length)).decode("utf-8")
+        stdout, stderr = StringIO(), StringIO()
+        sys.stdout, sys.stderr = stdout, stderr
+        value, error = None, None
+        try:
+            value = _run(source, inline == "1")
+        except BaseException as e:
+            # Drop the frames of this driver from the traceback.
+            tb = e.__traceback__
+            while tb is not None and tb.tb_frame.f_code.co_filename != "<chunk>":
+                tb = tb.tb_next
+            error = "".join(traceback.format_exception(type(e), e, tb))
+        finally:
+            sys.stdout, sys.stderr = sys.__stdout__, sys.__stderr__
+        _send(cookie, "stdout", stdout.getvalue())
+        _send(cookie, "stderr", stderr.getvalue())
+        if error is not None:
+            _send(cookie, "error", error)
+        if value is not None:
+            _send(cookie, "value", value)
+        _save_figures(cookie, prefix, width, height)
+        _send(cookie, "end", "")
+
+
+_main()
@@ -1,17 +1,30 @@
 use libR_sys::{
     cetype_t_CE_UTF8, setup_Rmainloop, R_CStackLimit, R_CleanTempDir, R_FindNamespace, R_GlobalEnv,
-    R_RunExitFinalizers, R_tryEval, Rf_ScalarString, Rf_findFun, Rf_initialize_R, Rf_install,
-    Rf_lang2, Rf_mkCharLenCE, Rf_mkString, Rf_protect, Rf_translateCharUTF8, Rf_unprotect_ptr, CDR,
-    SET_TAG, SEXPREC, STRING_ELT,
+    R_RunExitFinalizers, R_interrupts_pending, R_tryEval, Rf_ScalarString, Rf_findFun,
+    Rf_initialize_R, Rf_install, Rf_lang2, Rf_mkCharLenCE, Rf_mkString, Rf_protect,
+    Rf_translateCharUTF8, Rf_unprotect_ptr, CDR, SET_TAG, SEXPREC, STRING_ELT,
 };
 use rand::Rng;
-use std::{collections::HashMap, ffi::CStr, ops::Deref};
+use std::{
+    ffi::{CStr, CString},
+    ops::Deref,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, OnceLock,
+    },
+    thread,
+};
 use table::transform_tables;
 use tokio::{process::Command, sync::OnceCell};
 mod io;
 mod table;
 
-use typstpp_backend::Backend;
+use typstpp_backend::{
+    command_output, find_in_path, is_listing, Backend, BackendInfo, ChunkOptions, FigureOptions,
+    Graphics, OptionSpec, OptionType, OptionValue, FIGURE_OPTIONS,
+};
 
 struct RObj(*mut SEXPREC);
 
@@ -53,6 +66,8 @@ pub struct RBackend {
 pub enum Error {
     #[error("R error: {0}")]
     RError(&'static str),
+    #[error("failed to read plot {0}")]
+    Plot(String),
 }
 
 fn reindent(input: &str, output_from: String) -> String {
@@ -100,87 +115,193 @@ impl RBackend {
                 "Key must be alphanumeric, space, hyphen, or underscore",
             )));
         }
-        let knitr_char = unsafe { Rf_mkString("knitr\0".as_ptr() as *const i8) };
-        let knitr = unsafe { R_FindNamespace(knitr_char) };
-        let knitr = RObj::from(knitr);
-        let knit = unsafe { Rf_findFun(Rf_install("knit\0".as_ptr() as *const i8), *knitr) };
-        let knit = RObj::from(knit);
-        let source_wrapped = format!(
-            "```{{r {}}}\n{}\n```",
-            {
-                [
-                    input
-                        .options
-                        .echo
-                        .map(|b| format!("echo={}", if b { "TRUE" } else { "FALSE" })),
-                    input
-                        .options
-                        .eval
-                        .map(|b| format!("eval={}", if b { "TRUE" } else { "FALSE" })),
-                    input
-                        .options
-                        .error
-                        .map(|b| format!("error={}", if b { "TRUE" } else { "FALSE" })),
-                    input
-                        .options
-                        .include
-                        .map(|b| format!("include={}", if b { "TRUE" } else { "FALSE" })),
-                    input
-                        .options
-                        .message
-                        .map(|b| format!("message={}", if b { "TRUE" } else { "FALSE" })),
-                    Some(
-                        self.global_options
-                            .figure_path_prefix
-                            .as_ref()
-                            .map(|s| s.as_str().strip_suffix('/').unwrap_or(s))
-                            .unwrap_or("figures"),
-                    )
-                    .map(|s| format!("fig.path='{}/typstpp-{}-'", s, key)),
-                ]
-                .into_iter()
-                .flatten()
-                .collect::<Vec<_>>()
-                .join(", ")
-            },
-            input.source
-        );
-        let code = unsafe {
-            Rf_mkCharLenCE(
-                source_wrapped.as_ptr() as *const i8,
-                i32::try_from(source_wrapped.len()).unwrap(),
-                cetype_t_CE_UTF8,
-            )
+        // A listing is written by the driver from the whole source, so
+        // knitr must not echo it in pieces.
+        let listing = input.options.listing && !input.inline && input.options.echo != Some(false);
+        let echo = if listing {
+            Some(false)
+        } else {
+            input.options.echo
         };
-        let code = RObj::from(code);
-        let code_str = unsafe { Rf_ScalarString(*code) };
-        let code_str = RObj::from(code_str);
-        let call = unsafe { Rf_lang2(*knit, *code_str) };
-        let call = RObj::from(call);
-        unsafe {
-            SET_TAG(CDR(*call), Rf_install("text\0".as_ptr() as *const i8));
-        }
-        let mut error_occurred = 0;
+        // R objects must not live across an await, so everything touching R
+        // happens in this block, and the lock is released at its end.
+        let result = {
+            let knitr_char = unsafe { Rf_mkString("knitr\0".as_ptr() as *const i8) };
+            let knitr = unsafe { R_FindNamespace(knitr_char) };
+            let knitr = RObj::from(knitr);
+            let knit = unsafe { Rf_findFun(Rf_install("knit\0".as_ptr() as *const i8), *knitr) };
+            let knit = RObj::from(knit);
+            let source_wrapped = if input.inline {
+                format!("`r {}`", input.source)
+            } else {
+                format!(
+                    "```{{r {}}}\n{}\n```",
+                    {
+                        [
+                            echo.map(|b| format!("echo={}", if b { "TRUE" } else { "FALSE" })),
+                            input
+                                .options
+                                .eval
+                                .map(|b| format!("eval={}", if b { "TRUE" } else { "FALSE" })),
+                            input
+                                .options
+                                .error
+                                .map(|b| format!("error={}", if b { "TRUE" } else { "FALSE" })),
+                            input
+                                .options
+                                .include
+                                .map(|b| format!("include={}", if b { "TRUE" } else { "FALSE" })),
+                            input
+                                .options
+                                .message
+                                .map(|b| format!("message={}", if b { "TRUE" } else { "FALSE" })),
+                            input
+                                .options
+                                .figure
+                                .width
+                                .map(|w| format!("fig.width={}", w)),
+                            input
+                                .options
+                                .figure
+                                .height
+                                .map(|h| format!("fig.height={}", h)),
+                            Some(
+                                self.global_options
+                                    .figure_path_prefix
+                                    .as_ref()
+                                    .map(|s| s.as_str().strip_suffix('/').unwrap_or(s))
+                                    .unwrap_or("figures"),
+                            )
+                            .map(|s| {
+                                format!("fig.path={}", r_string(&format!("{}/typstpp-{}-", s, key)))
+                            }),
+                        ]
+                        .into_iter()
+                        .flatten()
+                        .chain(
+                            input
+                                .options
+                                .knitr
+                                .iter()
+                                .map(|(name, value)| format!("{}={}", name, value)),
+                        )
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                    },
+                    input.source
+                )
+            };
+            let code = unsafe {
+                Rf_mkCharLenCE(
+                    source_wrapped.as_ptr() as *const i8,
+                    i32::try_from(source_wrapped.len()).unwrap(),
+                    cetype_t_CE_UTF8,
+                )
+            };
+            let code = RObj::from(code);
+            let code_str = unsafe { Rf_ScalarString(*code) };
+            let code_str = RObj::from(code_str);
+            let call = unsafe { Rf_lang2(*knit, *code_str) };
+            let call = RObj::from(call);
+            unsafe {
+                SET_TAG(CDR(*call), Rf_install("text\0".as_ptr() as *const i8));
+            }
+            let mut error_occurred = 0;
 
-        let result = unsafe { R_tryEval(*call, R_GlobalEnv, &mut error_occurred) };
-        if error_occurred != 0 {
-            return Err(typstpp_backend::Error::BackendError(Error::RError(
-                "Error occurred",
-            )));
+            // R cannot be stopped from another thread directly, but it checks for
+            // a pending interrupt regularly and unwinds as if the user pressed
+            // Ctrl-C, which R_tryEval reports as an error.
+            let timed_out = Arc::new(AtomicBool::new(false));
+            let (done, finished) = mpsc::channel::<()>();
+            let timer = input.timeout.map(|timeout| {
+                let timed_out = timed_out.clone();
+                thread::spawn(move || {
+                    if finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                        timed_out.store(true, Ordering::SeqCst);
+                        unsafe { std::ptr::addr_of_mut!(R_interrupts_pending).write_volatile(1) };
+                    }
+                })
+            });
+            let result = unsafe { R_tryEval(*call, R_GlobalEnv, &mut error_occurred) };
+            drop(done);
+            if let Some(timer) = timer {
+                timer.join().ok();
+            }
+            if let (true, Some(timeout)) = (timed_out.load(Ordering::SeqCst), input.timeout) {
+                // The interrupt may have arrived after evaluation finished.
+                unsafe { std::ptr::addr_of_mut!(R_interrupts_pending).write_volatile(0) };
+                return Ok(vec![typstpp_backend::timed_out(timeout)]);
+            }
+            if error_occurred != 0 {
+                return Err(typstpp_backend::Error::BackendError(Error::RError(
+                    "Error occurred",
+                )));
+            }
+            let result = unsafe {
+                let result = STRING_ELT(result, 0);
+                let result = Rf_translateCharUTF8(result);
+                String::from_utf8(CStr::from_ptr(result).to_bytes().to_vec()).unwrap()
+            };
+            drop(r_lock);
+            result
+        };
+        if input.inline {
+            return Ok(vec![typstpp_backend::Output {
+                data: result.trim_end().to_string(),
+                ty: typstpp_backend::OutputType::Typst,
+            }]);
         }
-        let result = unsafe {
-            let result = STRING_ELT(result, 0);
-            let result = Rf_translateCharUTF8(result);
-            String::from_utf8(CStr::from_ptr(result).to_bytes().to_vec()).unwrap()
+        // Raw results are left alone, even if they look like a table.
+        let result = if input.options.asis {
+            result
+        } else {
+            transform_tables(&result)
         };
-        drop(r_lock);
-        let result = transform_tables(&result);
         let result = result.replace("```\n]\n#src[\n```r\n", "");
         let result = reindent(input.source, result);
-        Ok(vec![typstpp_backend::Output {
-            data: result,
-            ty: typstpp_backend::OutputType::Typst,
-        }])
+        let mut outputs = vec![];
+        if listing {
+            outputs.push(typstpp_backend::Output {
+                data: input.source.to_string(),
+                ty: typstpp_backend::OutputType::Code,
+            });
+        }
+        // The plot hook in `prelude.R` leaves the path of each plot between
+        // \x01 markers, so the text alternates between Typst and paths.
+        let plots = result.matches('\x01').count() / 2;
+        for (i, part) in result.split('\x01').enumerate() {
+            if i % 2 == 1 {
+                let graphics =
+                    Graphics::take_file(Path::new(part), input.options.figure.figure(i / 2, plots))
+                        .await
+                        .map_err(|e| {
+                            typstpp_backend::Error::BackendError(Error::Plot(format!(
+                                "{}: {}",
+                                part, e
+                            )))
+                        })?;
+                outputs.push(typstpp_backend::Output {
+                    data: String::new(),
+                    ty: typstpp_backend::OutputType::Graphics(graphics),
+                });
+            } else {
+                // The error hook leaves each error between \x02 markers.
+                for (j, part) in part.split('\x02').enumerate() {
+                    if j % 2 == 1 {
+                        outputs.push(typstpp_backend::Output {
+                            data: part.trim().to_string(),
+                            ty: typstpp_backend::OutputType::Error,
+                        });
+                    } else if !part.trim().is_empty() {
+                        outputs.push(typstpp_backend::Output {
+                            data: part.to_string(),
+                            ty: typstpp_backend::OutputType::Typst,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(outputs)
     }
 }
 
@@ -191,25 +312,136 @@ pub struct ROptions {
     error: Option<bool>,
     include: Option<bool>,
     message: Option<bool>,
+    figure: FigureOptions,
+    /// Whether the source is shown as one listing rather than interleaved
+    /// with its output by knitr.
+    listing: bool,
+    /// Other options, passed on to knitr as they are: knitr names and R
+    /// values.
+    knitr: Vec<(String, String)>,
+    /// Whether the results are written as they are, with `output: asis` or
+    /// knitr's own `results: asis`.
+    asis: bool,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct RGlobalOptions {
     pub figure_path_prefix: Option<String>,
+    /// The `R` front end of the installation to embed, used to find
+    /// `R_HOME` when it is not set; `R` from `PATH` by default.
+    pub command: Option<String>,
+    /// Extra arguments R is initialized with. R is initialized once per
+    /// process, so only the first backend's arguments take effect.
+    pub args: Vec<String>,
 }
 
-impl From<HashMap<String, String>> for ROptions {
-    fn from(m: HashMap<String, String>) -> Self {
+impl RGlobalOptions {
+    fn command(&self) -> &str {
+        self.command.as_deref().unwrap_or("R")
+    }
+}
+
+impl From<ChunkOptions> for ROptions {
+    fn from(options: ChunkOptions) -> Self {
+        let results = options.string("results");
+        let output = options.string("output").map(str::to_ascii_lowercase);
+        let asis = results == Some("asis") || output.as_deref() == Some("asis");
+        let mut knitr = options
+            .undeclared()
+            .map(|(name, value)| (name.replace('-', "."), r_value(value)))
+            // Plots have to be where the backend looks for them.
+            .filter(|(name, _)| name != "fig.path")
+            .collect::<Vec<_>>();
+        if results.is_none() {
+            match output.as_deref() {
+                Some("asis") => knitr.push(("results".to_string(), r_string("asis"))),
+                Some("false" | "no") => knitr.push(("results".to_string(), r_string("hide"))),
+                _ => {}
+            }
+        }
         ROptions {
-            echo: m.get("echo").map(|s| s.parse().unwrap()),
-            eval: m.get("eval").map(|s| s.parse().unwrap()),
-            error: m.get("error").map(|s| s.parse().unwrap()),
-            include: m.get("include").map(|s| s.parse().unwrap()),
-            message: m.get("message").map(|s| s.parse().unwrap()),
+            echo: options.bool("echo"),
+            eval: options.bool("eval"),
+            error: options.bool("error"),
+            include: options.bool("include"),
+            message: options.bool("message"),
+            figure: FigureOptions::from(&options),
+            listing: is_listing(&options),
+            knitr,
+            asis,
+        }
+    }
+}
+
+/// `s` as an R string literal, on one line.
+fn r_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// An option value as an R expression, on one line so that it fits in a
+/// knitr chunk header.
+fn r_value(value: &OptionValue) -> String {
+    match value {
+        OptionValue::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        OptionValue::Number(n) if n.is_nan() => "NaN".to_string(),
+        OptionValue::Number(n) if n.is_infinite() => {
+            if *n > 0.0 { "Inf" } else { "-Inf" }.to_string()
+        }
+        OptionValue::Number(n) => n.to_string(),
+        OptionValue::String(s) => r_string(s),
+        OptionValue::List(items) => {
+            let nested = items.iter().any(|v| matches!(v, OptionValue::List(_)));
+            format!(
+                "{}({})",
+                if nested { "list" } else { "c" },
+                items.iter().map(r_value).collect::<Vec<_>>().join(", ")
+            )
         }
+        OptionValue::Expr(e) => e.replace(['\r', '\n'], " "),
     }
 }
 
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "echo",
+        ty: OptionType::Bool,
+        doc: "Show the source of the chunk.",
+    },
+    OptionSpec {
+        name: "eval",
+        ty: OptionType::Bool,
+        doc: "Run the chunk.",
+    },
+    OptionSpec {
+        name: "include",
+        ty: OptionType::Bool,
+        doc: "Include the chunk and its results in the output.",
+    },
+    OptionSpec {
+        name: "message",
+        ty: OptionType::Bool,
+        doc: "Show messages emitted by the chunk.",
+    },
+    OptionSpec {
+        name: OptionSpec::ANY,
+        ty: OptionType::Expr,
+        doc: "Any other knitr chunk option, such as `results` or `fig.align`.",
+    },
+];
+
 static mut R_INITIALIZED: OnceCell<Result<(), typstpp_backend::Error<Error>>> =
     OnceCell::const_new();
 
@@ -235,10 +467,12 @@ impl Backend for RBackend {
                 .lock()
                 .await;
 
+            let command = global_options.command().to_string();
+            let extra_args = global_options.args.clone();
             let r = R_INITIALIZED
-                .get_or_init(|| async {
+                .get_or_init(|| async move {
                     if std::env::var("R_HOME").is_err() {
-                        let out = Command::new("R")
+                        let out = Command::new(command)
                             .arg("-s")
                             .arg("-e")
                             .arg("cat(normalizePath(R.home()))")
@@ -256,12 +490,22 @@ impl Backend for RBackend {
                             }
                         }
                     }
-                    if Rf_initialize_R(
-                        3,
-                        ["R\0".as_ptr(), "--slave\0".as_ptr(), "--silent\0".as_ptr()].as_ptr()
-                            as *mut *mut i8,
-                    ) != 0
-                    {
+                    let Ok(args) = ["R", "--slave", "--silent"]
+                        .into_iter()
+                        .map(str::to_string)
+                        .chain(extra_args)
+                        .map(CString::new)
+                        .collect::<Result<Vec<_>, _>>()
+                    else {
+                        return Err(typstpp_backend::Error::BackendError(Error::RError(
+                            "R arguments must not contain NUL",
+                        )));
+                    };
+                    let mut argv = args
+                        .iter()
+                        .map(|a| a.as_ptr() as *mut i8)
+                        .collect::<Vec<_>>();
+                    if Rf_initialize_R(i32::try_from(argv.len()).unwrap(), argv.as_mut_ptr()) != 0 {
                         return Err(typstpp_backend::Error::BackendError(Error::RError(
                             "Failed to initialize R",
                         )));
@@ -312,14 +556,64 @@ impl Backend for RBackend {
         }
     }
 
+    fn option_schema() -> &'static [OptionSpec] {
+        static SCHEMA: OnceLock<Vec<OptionSpec>> = OnceLock::new();
+        SCHEMA.get_or_init(|| [OPTIONS, FIGURE_OPTIONS].concat())
+    }
+
+    async fn probe(global_options: &Self::GlobalOptions) -> BackendInfo {
+        // R runs embedded in this process, but the `R` front end of the
+        // same installation tells which versions it would load.
+        let r = match std::env::var_os("R_HOME") {
+            Some(home) => Some(std::path::Path::new(&home).join("bin").join("R")),
+            None => find_in_path(global_options.command()),
+        };
+        let Some(r) = r.filter(|r| r.is_file()) else {
+            return BackendInfo::missing("R not found in R_HOME or PATH");
+        };
+        let mut info = BackendInfo {
+            path: Some(r.clone()),
+            ..Default::default()
+        };
+        if let Some(v) = command_output(
+            &r,
+            &[
+                "--slave",
+                "-e",
+                "cat(paste(R.version$major, R.version$minor, sep = '.'))",
+            ],
+        )
+        .await
+        {
+            info.versions.push(("R".to_string(), v));
+        }
+        match command_output(
+            &r,
+            &[
+                "--slave",
+                "-e",
+                "cat(as.character(packageVersion('knitr')))",
+            ],
+        )
+        .await
+        {
+            Some(v) => info.versions.push(("knitr".to_string(), v)),
+            None => info.missing = Some("the knitr package is not installed".to_string()),
+        }
+        info
+    }
+
     async fn compile<'a>(
         &mut self,
         input: Vec<typstpp_backend::Input<'a, Self::Options>>,
     ) -> Result<Vec<Vec<typstpp_backend::Output<Self::Output>>>, typstpp_backend::Error<Self::Error>>
     {
         let mut outputs = Vec::new();
-        for (i, input) in input.into_iter().enumerate() {
-            outputs.push(self.pass(format!("chunk-{}", i).as_str(), input).await?);
+        for input in input {
+            outputs.push(
+                self.pass(format!("chunk-{}", input.index).as_str(), input)
+                    .await?,
+            );
         }
         Ok(outputs)
     }
@@ -361,6 +655,72 @@ impl Backend for RBackend {
 mod tests {
     use super::*;
     use typstpp_backend::Backend;
+
+    #[test]
+    fn test_knitr_options() {
+        let options = ChunkOptions::parse(
+            [
+                ("echo", "false"),
+                ("fig-cap", "not passed on"),
+                ("results", "asis"),
+                ("comment", "'a \"quoted\" } comment'"),
+                ("dev-args", "!expr list(bg = \"white\")"),
+                ("fig-path", "elsewhere/"),
+                ("out-extra", "[\"a\", 1]"),
+            ],
+            RBackend::option_schema(),
+        )
+        .unwrap();
+        assert_eq!(
+            ROptions::from(options).knitr,
+            [
+                ("comment", r#""a \"quoted\" } comment""#),
+                ("dev.args", r#"list(bg = "white")"#),
+                ("out.extra", r#"c("a", 1)"#),
+                ("results", r#""asis""#),
+            ]
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+        );
+        assert_eq!(r_string("a\nb\\"), r#""a\nb\\""#);
+    }
+
+    #[test]
+    fn test_asis_options() {
+        let parse = |options: &[(&str, &str)]| {
+            ROptions::from(
+                ChunkOptions::parse(
+                    options.iter().copied(),
+                    &[
+                        RBackend::option_schema(),
+                        &[OptionSpec {
+                            name: "output",
+                            ty: OptionType::String,
+                            doc: "",
+                        }],
+                    ]
+                    .concat(),
+                )
+                .unwrap(),
+            )
+        };
+        let options = parse(&[("output", "asis")]);
+        assert!(options.asis);
+        assert_eq!(
+            options.knitr,
+            [("results".to_string(), r#""asis""#.to_string())]
+        );
+        let options = parse(&[("results", "asis")]);
+        assert!(options.asis);
+        assert_eq!(options.knitr.len(), 1);
+        assert!(!parse(&[("results", "hold")]).asis);
+        let options = parse(&[("output", "false")]);
+        assert!(!options.asis);
+        assert_eq!(
+            options.knitr,
+            [("results".to_string(), r#""hide""#.to_string())]
+        );
+    }
+
     #[tokio::test]
     async fn test_r_backend() {
         let mut backend = RBackend::new(RGlobalOptions::default())
@@ -372,6 +732,9 @@ mod tests {
                 typstpp_backend::Input {
                     source: "print('hello')",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
@@ -390,6 +753,9 @@ mod tests {
                 typstpp_backend::Input {
                     source: "a <- 1+1\nprint(a)",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
@@ -409,6 +775,9 @@ mod tests {
                 typstpp_backend::Input {
                     source: "a <- 1",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
@@ -426,6 +795,9 @@ mod tests {
                 typstpp_backend::Input {
                     source: "print(a)",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
@@ -446,12 +818,18 @@ mod tests {
                 typstpp_backend::Input {
                     source: "print(a)",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
             .unwrap();
 
-        assert!(result[0].data.contains("Error"))
+        assert!(result
+            .iter()
+            .any(|o| o.ty == typstpp_backend::OutputType::Error
+                && o.data.contains("object 'a' not found")));
     }
 
     #[tokio::test]
@@ -459,6 +837,7 @@ mod tests {
         let tmpdir = tempfile::tempdir().expect("Failed to create figure tempdir");
         let mut backend = RBackend::new(RGlobalOptions {
             figure_path_prefix: Some(tmpdir.path().to_str().unwrap().to_string()),
+            ..Default::default()
         })
         .await
         .expect("Failed to create R backend");
@@ -468,11 +847,194 @@ mod tests {
                 typstpp_backend::Input {
                     source: "plot(1:10)\nprint('hello')\nplot(10:1)",
                     options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+            )
+            .await
+            .unwrap();
+        let plots = result
+            .iter()
+            .filter_map(|o| match &o.ty {
+                typstpp_backend::OutputType::Graphics(g) => Some(g),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(plots.len(), 2);
+        assert!(plots
+            .iter()
+            .all(|g| g.ty == typstpp_backend::GraphicsType::Svg && !g.data.is_empty()));
+        assert!(result
+            .iter()
+            .any(|o| o.ty == typstpp_backend::OutputType::Typst && o.data.contains("hello")));
+        // The plot files are read back and removed.
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_r_figures() {
+        let tmpdir = tempfile::tempdir().expect("Failed to create figure tempdir");
+        let mut backend = RBackend::new(RGlobalOptions {
+            figure_path_prefix: Some(tmpdir.path().to_str().unwrap().to_string()),
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create R backend");
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "plot(1:10)\nplot(10:1)",
+                    options: ROptions {
+                        figure: FigureOptions {
+                            label: Some("fig-lines".to_string()),
+                            caption: vec!["Up".to_string(), "Down".to_string()],
+                            out_width: Some("50%".to_string()),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+            )
+            .await
+            .unwrap();
+        let figures = result
+            .iter()
+            .filter_map(|o| match &o.ty {
+                typstpp_backend::OutputType::Graphics(g) => Some(g.figure.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            figures
+                .iter()
+                .map(|f| (f.label.as_deref(), f.caption.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                (Some("fig-lines-1"), Some("Up")),
+                (Some("fig-lines-2"), Some("Down"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_r_listing() {
+        let mut backend = RBackend::new(RGlobalOptions::default())
+            .await
+            .expect("Failed to create R backend");
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "a <- 1\nprint(a)",
+                    options: ROptions {
+                        listing: true,
+                        ..Default::default()
+                    },
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            result[0],
+            typstpp_backend::Output {
+                data: "a <- 1\nprint(a)".to_string(),
+                ty: typstpp_backend::OutputType::Code,
+            }
+        );
+        assert!(result[1].data.contains("## [1] 1"));
+        assert!(!result[1].data.contains("#src"));
+    }
+
+    #[tokio::test]
+    async fn test_r_inline() {
+        let mut backend = RBackend::new(RGlobalOptions::default())
+            .await
+            .expect("Failed to create R backend");
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "1 + 1",
+                    options: ROptions::default(),
+                    inline: true,
+                    index: 0,
+                    timeout: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![typstpp_backend::Output {
+                data: "2".to_string(),
+                ty: typstpp_backend::OutputType::Typst,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_r_errors() {
+        let mut backend = RBackend::new(RGlobalOptions::default())
+            .await
+            .expect("Failed to create R backend");
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "stop('boom')",
+                    options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert!(result
+            .iter()
+            .any(|o| o.ty == typstpp_backend::OutputType::Error && o.data.contains("boom")));
+    }
+
+    #[tokio::test]
+    async fn test_r_timeout() {
+        let mut backend = RBackend::new(RGlobalOptions::default())
+            .await
+            .expect("Failed to create R backend");
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "while (TRUE) {}",
+                    options: ROptions::default(),
+                    inline: false,
+                    index: 0,
+                    timeout: Some(std::time::Duration::from_millis(200)),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(result[0].ty, typstpp_backend::OutputType::Error);
+        let result = backend
+            .pass(
+                "test",
+                typstpp_backend::Input {
+                    source: "1 + 1",
+                    options: ROptions::default(),
+                    inline: true,
+                    index: 0,
+                    timeout: None,
                 },
             )
             .await
             .unwrap();
-        assert!(result[0].ty == typstpp_backend::OutputType::Typst);
-        assert!(result[0].data.contains(tmpdir.path().to_str().unwrap()));
+        assert_eq!(result[0].data, "2");
     }
 }
@@ -0,0 +1,514 @@
+use serde::Deserialize;
+use std::{process::Stdio, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+};
+use typstpp_backend::{
+    find_in_path, new_cookie, read_until_cookie, Backend, BackendInfo, ChunkOptions, Input,
+    OptionSpec, OptionType,
+};
+
+/// How to drive an interpreter as a REPL.
+///
+/// For example, for bash:
+///
+/// ```toml
+/// [repl.bash]
+/// command = "bash"
+/// cookie = "echo {cookie}"
+/// stderr-cookie = "echo {cookie} >&2"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReplSpec {
+    /// The interpreter to run.
+    pub command: String,
+    /// Arguments passed to the interpreter.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Appended after every chunk and every cookie statement.
+    #[serde(default = "default_terminator")]
+    pub terminator: String,
+    /// A statement that prints `{cookie}` to stdout.
+    pub cookie: String,
+    /// A statement that prints `{cookie}` to stderr.
+    ///
+    /// Without it, stderr is collected in the background and whatever has
+    /// arrived when a chunk finishes is attributed to that chunk.
+    pub stderr_cookie: Option<String>,
+    /// The prefix of the lines the interpreter prints to stderr for
+    /// warnings. A chunk whose stderr consists of such lines only shows
+    /// them as a message; any other stderr is an error.
+    pub warnings: Option<String>,
+    /// Statements run once when the interpreter starts.
+    #[serde(default)]
+    pub init: Vec<String>,
+    /// Statements that clear the interpreter's state between `watch`
+    /// rebuilds, after which `init` runs again. Without them, the
+    /// interpreter is restarted instead.
+    pub reset: Option<String>,
+    /// Prompts the interpreter prints, stripped from the start of each
+    /// output line.
+    #[serde(default)]
+    pub prompts: Vec<String>,
+    /// Whether the interpreter can run while other languages are running,
+    /// e.g. `false` if it competes with them for a license or a database.
+    #[serde(default = "default_concurrent")]
+    pub concurrent: bool,
+}
+
+impl ReplSpec {
+    /// Whether every line of `stderr` is a warning.
+    fn is_warning(&self, stderr: &str) -> bool {
+        let Some(prefix) = &self.warnings else {
+            return false;
+        };
+        stderr
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .all(|l| l.starts_with(prefix.as_str()))
+    }
+}
+
+fn default_terminator() -> String {
+    "\n".to_string()
+}
+
+fn default_concurrent() -> bool {
+    true
+}
+
+pub struct ReplBackend {
+    spec: ReplSpec,
+    session: Option<ReplSession>,
+}
+
+pub struct ReplOptions {
+    echo: bool,
+    eval: bool,
+}
+
+impl From<ChunkOptions> for ReplOptions {
+    fn from(options: ChunkOptions) -> Self {
+        ReplOptions {
+            echo: options.bool("echo").unwrap_or(true),
+            eval: options.bool("eval").unwrap_or(true),
+        }
+    }
+}
+
+const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "echo",
+        ty: OptionType::Bool,
+        doc: "Show the source of the chunk.",
+    },
+    OptionSpec {
+        name: "eval",
+        ty: OptionType::Bool,
+        doc: "Run the chunk.",
+    },
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("REPL session error: {0}")]
+    SessionError(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::SessionError(format!("{}", e))
+    }
+}
+
+enum Stderr {
+    Cookie(BufReader<ChildStderr>),
+    Background(Arc<Mutex<String>>),
+}
+
+struct ReplSession {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: Stderr,
+}
+
+impl ReplSession {
+    async fn spawn(spec: &ReplSpec) -> Result<Self, Error> {
+        let mut child = Command::new(&spec.command)
+            .args(&spec.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::SessionError(format!("failed to start {}: {}", spec.command, e)))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let stderr = child.stderr.take().unwrap();
+        let stderr = if spec.stderr_cookie.is_some() {
+            Stderr::Cookie(BufReader::new(stderr))
+        } else {
+            let buf = Arc::new(Mutex::new(String::new()));
+            let sink = buf.clone();
+            tokio::spawn(async move {
+                let mut stderr = stderr;
+                let mut chunk = [0; 4096];
+                while let Ok(n @ 1..) = stderr.read(&mut chunk).await {
+                    sink.lock()
+                        .await
+                        .push_str(&String::from_utf8_lossy(&chunk[..n]));
+                }
+            });
+            Stderr::Background(buf)
+        };
+        let mut session = ReplSession {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        };
+        session.run(spec, &spec.init.join(&spec.terminator)).await?;
+        Ok(session)
+    }
+
+    /// Send `code` to the interpreter and collect what it printed to stdout
+    /// and stderr.
+    async fn run(&mut self, spec: &ReplSpec, code: &str) -> Result<(String, String), Error> {
+        let cookie = new_cookie();
+        let mut command = String::new();
+        if !code.trim().is_empty() {
+            command.push_str(code);
+            command.push_str(&spec.terminator);
+        }
+        command.push_str(&spec.cookie.replace("{cookie}", &cookie));
+        command.push_str(&spec.terminator);
+        if let Some(stderr_cookie) = &spec.stderr_cookie {
+            command.push_str(&stderr_cookie.replace("{cookie}", &cookie));
+            command.push_str(&spec.terminator);
+        }
+        self.stdin.write_all(command.as_bytes()).await?;
+        self.stdin.flush().await?;
+        let (stdout, stderr) = match &mut self.stderr {
+            Stderr::Cookie(stderr) => {
+                let (stdout, stderr) = tokio::join!(
+                    read_until_cookie(&mut self.stdout, &cookie),
+                    read_until_cookie(stderr, &cookie)
+                );
+                (stdout?, stderr?)
+            }
+            Stderr::Background(buf) => {
+                let stdout = read_until_cookie(&mut self.stdout, &cookie).await?;
+                (stdout, std::mem::take(&mut *buf.lock().await))
+            }
+        };
+        Ok((
+            strip_prompts(&stdout, &spec.prompts),
+            strip_prompts(&stderr, &spec.prompts),
+        ))
+    }
+
+    async fn quit(mut self) -> Result<(), Error> {
+        drop(self.stdin);
+        self.child.wait().await?;
+        Ok(())
+    }
+}
+
+fn strip_prompts(output: &str, prompts: &[String]) -> String {
+    if prompts.is_empty() {
+        return output.to_string();
+    }
+    let mut stripped = String::new();
+    for line in output.split_inclusive('\n') {
+        let mut line = line;
+        while let Some(rest) = prompts
+            .iter()
+            .find_map(|p| line.strip_prefix(p.as_str()).filter(|_| !p.is_empty()))
+        {
+            line = rest;
+        }
+        stripped.push_str(line);
+    }
+    stripped
+}
+
+impl ReplBackend {
+    /// The running interpreter, started on first use.
+    async fn session(&mut self) -> Result<&mut ReplSession, Error> {
+        if self.session.is_none() {
+            self.session = Some(ReplSession::spawn(&self.spec).await?);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    async fn close_session(&mut self) -> Result<(), typstpp_backend::Error<Error>> {
+        if let Some(session) = self.session.take() {
+            session
+                .quit()
+                .await
+                .map_err(typstpp_backend::Error::BackendError)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for ReplBackend {
+    type GlobalOptions = ReplSpec;
+    type Options = ReplOptions;
+    type Output = String;
+    type Error = Error;
+
+    async fn new<'a>(
+        global_options: Self::GlobalOptions,
+    ) -> Result<Self, typstpp_backend::Error<Self::Error>>
+    where
+        Self: Sized,
+    {
+        Ok(ReplBackend {
+            spec: global_options,
+            session: None,
+        })
+    }
+
+    fn option_schema() -> &'static [OptionSpec] {
+        OPTIONS
+    }
+
+    async fn probe(global_options: &Self::GlobalOptions) -> BackendInfo {
+        match find_in_path(&global_options.command) {
+            Some(path) => BackendInfo {
+                path: Some(path),
+                ..Default::default()
+            },
+            None => BackendInfo::missing(format!("{} not found", global_options.command)),
+        }
+    }
+
+    fn concurrent(&self) -> bool {
+        self.spec.concurrent
+    }
+
+    async fn compile<'a>(
+        &mut self,
+        input: Vec<Input<'a, Self::Options>>,
+    ) -> Result<Vec<Vec<typstpp_backend::Output<Self::Output>>>, typstpp_backend::Error<Self::Error>>
+    {
+        let spec = self.spec.clone();
+        let mut outputs = vec![];
+        for input in input {
+            let mut chunk_output = vec![];
+            if input.options.echo && !input.inline {
+                chunk_output.push(typstpp_backend::Output {
+                    data: input.source.to_string(),
+                    ty: typstpp_backend::OutputType::Code,
+                });
+            }
+            if input.options.eval {
+                let run = self
+                    .session()
+                    .await
+                    .map_err(typstpp_backend::Error::BackendError)?
+                    .run(&spec, input.source.trim_end());
+                let result = match typstpp_backend::run_with_timeout(input.timeout, run).await {
+                    Ok(result) => result,
+                    Err(timed_out) => {
+                        self.session = None;
+                        chunk_output.push(timed_out);
+                        outputs.push(chunk_output);
+                        continue;
+                    }
+                };
+                let (stdout, stderr) = match result {
+                    Ok(result) => result,
+                    Err(e) => {
+                        // The interpreter exited or fell out of step with the cookies;
+                        // a fresh session is started for the next chunk.
+                        self.session = None;
+                        return Err(typstpp_backend::Error::BackendError(e));
+                    }
+                };
+                if !stderr.is_empty() {
+                    let ty = if spec.is_warning(&stderr) {
+                        typstpp_backend::OutputType::Message
+                    } else {
+                        typstpp_backend::OutputType::Error
+                    };
+                    chunk_output.push(typstpp_backend::Output { data: stderr, ty });
+                }
+                if input.inline {
+                    chunk_output.push(typstpp_backend::Output {
+                        data: stdout.trim_end().to_string(),
+                        ty: typstpp_backend::OutputType::Typst,
+                    });
+                } else if !stdout.is_empty() {
+                    chunk_output.push(typstpp_backend::Output {
+                        data: stdout,
+                        ty: typstpp_backend::OutputType::Output,
+                    });
+                }
+            }
+            outputs.push(chunk_output);
+        }
+        Ok(outputs)
+    }
+
+    async fn reset(&mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
+        let spec = self.spec.clone();
+        match (self.session.as_mut(), &spec.reset) {
+            (None, _) => Ok(()),
+            (Some(session), Some(reset)) => {
+                let code = [reset.as_str()]
+                    .into_iter()
+                    .chain(spec.init.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(&spec.terminator);
+                if let Err(e) = session.run(&spec, &code).await {
+                    self.session = None;
+                    return Err(typstpp_backend::Error::BackendError(e));
+                }
+                Ok(())
+            }
+            // There is no generic way to clear an interpreter's state, so
+            // start a fresh one on the next chunk.
+            (Some(_), None) => self.close_session().await,
+        }
+    }
+
+    async fn close(mut self) -> Result<(), typstpp_backend::Error<Self::Error>> {
+        self.close_session().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bash(stderr_cookie: bool) -> ReplSpec {
+        ReplSpec {
+            command: "bash".to_string(),
+            args: vec![],
+            terminator: default_terminator(),
+            cookie: "echo {cookie}".to_string(),
+            stderr_cookie: stderr_cookie.then(|| "echo {cookie} >&2".to_string()),
+            warnings: None,
+            init: vec!["x=41".to_string()],
+            reset: None,
+            prompts: vec![],
+            concurrent: true,
+        }
+    }
+
+    fn input(source: &str) -> Input<'_, ReplOptions> {
+        Input {
+            source,
+            options: ReplOptions {
+                echo: false,
+                eval: true,
+            },
+            inline: false,
+            index: 0,
+            timeout: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repl_backend() {
+        let mut backend = ReplBackend::new(bash(true)).await.unwrap();
+        let outputs = backend
+            .compile(vec![input("x=$((x + 1))"), input("echo $x\necho oops >&2")])
+            .await
+            .unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                vec![],
+                vec![
+                    typstpp_backend::Output {
+                        data: "oops\n".to_string(),
+                        ty: typstpp_backend::OutputType::Error,
+                    },
+                    typstpp_backend::Output {
+                        data: "42\n".to_string(),
+                        ty: typstpp_backend::OutputType::Output,
+                    }
+                ]
+            ]
+        );
+
+        backend.reset().await.unwrap();
+        let outputs = backend.compile(vec![input("echo $x")]).await.unwrap();
+        assert_eq!(outputs[0][0].data, "41\n");
+    }
+
+    #[tokio::test]
+    async fn test_repl_reset_command() {
+        let mut spec = bash(true);
+        spec.reset = Some("unset x y".to_string());
+        let mut backend = ReplBackend::new(spec).await.unwrap();
+        backend.compile(vec![input("y=1")]).await.unwrap();
+        let pid = backend.session.as_ref().unwrap().child.id();
+
+        backend.reset().await.unwrap();
+        let outputs = backend.compile(vec![input("echo $x $y")]).await.unwrap();
+        assert_eq!(outputs[0][0].data, "41\n");
+        assert_eq!(backend.session.as_ref().unwrap().child.id(), pid);
+    }
+
+    #[tokio::test]
+    async fn test_repl_restarts_after_exit() {
+        let mut backend = ReplBackend::new(bash(true)).await.unwrap();
+        assert!(backend.compile(vec![input("exit")]).await.is_err());
+        let outputs = backend.compile(vec![input("echo $x")]).await.unwrap();
+        assert_eq!(outputs[0][0].data, "41\n");
+    }
+
+    #[tokio::test]
+    async fn test_repl_warnings() {
+        let warning = "echo 'warning: careful' >&2";
+        let mut backend = ReplBackend::new(bash(true)).await.unwrap();
+        let outputs = backend.compile(vec![input(warning)]).await.unwrap();
+        assert_eq!(outputs[0][0].ty, typstpp_backend::OutputType::Error);
+
+        let mut spec = bash(true);
+        spec.warnings = Some("warning:".to_string());
+        let mut backend = ReplBackend::new(spec).await.unwrap();
+        let outputs = backend
+            .compile(vec![input(warning), input("echo 'warning threshold' >&2")])
+            .await
+            .unwrap();
+        assert_eq!(outputs[0][0].ty, typstpp_backend::OutputType::Message);
+        assert_eq!(outputs[1][0].ty, typstpp_backend::OutputType::Error);
+    }
+
+    #[tokio::test]
+    async fn test_repl_without_stderr_cookie() {
+        let mut backend = ReplBackend::new(bash(false)).await.unwrap();
+        let outputs = backend.compile(vec![input("echo $x")]).await.unwrap();
+        assert_eq!(outputs[0][0].data, "41\n");
+    }
+
+    #[tokio::test]
+    async fn test_repl_timeout() {
+        let mut backend = ReplBackend::new(bash(true)).await.unwrap();
+        let mut slow = input("x=0; sleep 10");
+        slow.timeout = Some(std::time::Duration::from_millis(200));
+        let outputs = backend.compile(vec![slow, input("echo $x")]).await.unwrap();
+        assert_eq!(outputs[0][0].ty, typstpp_backend::OutputType::Error);
+        assert!(outputs[0][0].data.contains("timed out"));
+        // The interpreter was restarted, so the assignment is gone.
+        assert_eq!(outputs[1][0].data, "41\n");
+    }
+
+    #[test]
+    fn test_strip_prompts() {
+        assert_eq!(
+            strip_prompts("> > 1\n... 2\n", &["> ".to_string(), "... ".to_string()]),
+            "1\n2\n"
+        );
+    }
+}
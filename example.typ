@@ -4,40 +4,40 @@
 
 Load some libraries:
 
-```r
-#| message: false
+```{r setup, message=FALSE}
 library(tidyverse)
 ```
 
 
 Then make a plot:
 
-```r
+```{r}
+#| label: iris-plot
 plot(iris)
 ```
 
 Then try some Haskell:
 
-```hs
-:{
-  fib :: Int -> Int
-  fib 0 = 0
-  fib 1 = 1
-  fib n = fib (n-1) + fib (n-2)
-:}
+```{hs}
+fib :: Int -> Int
+fib 0 = 0
+fib 1 = 1
+fib n = fib (n-1) + fib (n-2)
 
 map fib [0..10]
 ```
 
+The iris dataset has `r nrow(iris)` rows.
+
 Then make a table:
 
-```r
+```{r}
 knitr::kable(head(iris))
 ```
 
 Mix some code, plots and tables in the same chunk:
 
-```r
+```{r}
 factorial <- function(n) {
   if (n == 0) {
     return(1)
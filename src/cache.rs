@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use typstpp_backend::OutputType;
+
+use crate::{source::RawOption, CodeOutput, Error};
+
+/// A content-addressed store of chunk results.
+///
+/// A chunk's key covers its language, code, whether it is inline, its
+/// options, the contents of the files it depends on and the key of the
+/// chunk before it in the same session, so editing a chunk invalidates
+/// every chunk of that language after it.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    errors: Vec<String>,
+    /// The outputs, with the data of images moved to files of their own.
+    outputs: Vec<typstpp_backend::Output<String>>,
+    /// The files holding the images of the outputs, in order.
+    graphics: Vec<PathBuf>,
+}
+
+fn graphics_mut(
+    outputs: &mut [typstpp_backend::Output<String>],
+) -> impl Iterator<Item = &mut typstpp_backend::Graphics> {
+    outputs.iter_mut().filter_map(|o| match &mut o.ty {
+        OutputType::Graphics(g) => Some(g),
+        _ => None,
+    })
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    pub fn key(
+        lang: &str,
+        code: &str,
+        inline: bool,
+        options: &[RawOption],
+        dependencies: &[Vec<u8>],
+        previous: Option<&str>,
+    ) -> String {
+        let mut hasher = Sha256::new();
+        for part in [lang, code, previous.unwrap_or("")] {
+            hasher.update(part.len().to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.update([inline as u8]);
+        for part in dependencies {
+            hasher.update(part.len().to_le_bytes());
+            hasher.update(part);
+        }
+        let mut options = options
+            .iter()
+            .map(|o| (&o.name, &o.value))
+            .collect::<Vec<_>>();
+        options.sort();
+        for (k, v) in options {
+            hasher.update(k.len().to_le_bytes());
+            hasher.update(k.as_bytes());
+            hasher.update(v.len().to_le_bytes());
+            hasher.update(v.as_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// Look up a result, along with the images it produced.
+    pub async fn get(&self, key: &str) -> Option<CodeOutput<String>> {
+        let entry = fs::read(self.entry_path(key)).await.ok()?;
+        let mut entry: Entry = serde_json::from_slice(&entry).ok()?;
+        for (g, file) in graphics_mut(&mut entry.outputs).zip(&entry.graphics) {
+            g.data = fs::read(self.dir.join(file)).await.ok()?;
+        }
+        Some(CodeOutput {
+            errors: entry.errors,
+            outputs: entry.outputs,
+            elapsed: None,
+            cached: true,
+        })
+    }
+
+    /// Store a result, keeping its images next to it.
+    pub async fn put(&self, key: &str, output: &CodeOutput<String>) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir).await?;
+        let mut outputs = output.outputs.clone();
+        let mut graphics = Vec::new();
+        for g in graphics_mut(&mut outputs) {
+            let file = PathBuf::from(format!("{}-{}.{}", key, graphics.len(), g.ty.extension()));
+            fs::write(self.dir.join(&file), std::mem::take(&mut g.data)).await?;
+            graphics.push(file);
+        }
+        let entry = Entry {
+            errors: output.errors.clone(),
+            outputs,
+            graphics,
+        };
+        fs::write(
+            self.entry_path(key),
+            serde_json::to_vec(&entry).map_err(|e| Error::RuntimeError(format!("{}", e)))?,
+        )
+        .await?;
+        Ok(())
+    }
+}
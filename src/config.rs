@@ -0,0 +1,322 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer};
+use typstpp_backend::parse_duration;
+use typstpp_repl::ReplSpec;
+
+use crate::Error;
+
+/// Project settings read from `typstpp.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// The directory of `typstpp.toml`, or of the document if there is
+    /// none; relative paths in the file are relative to it.
+    #[serde(skip)]
+    pub root: PathBuf,
+    /// The documents processed when no input is given on the command line.
+    #[serde(default)]
+    pub entries: Vec<PathBuf>,
+    /// Where compiled documents are written; next to the preprocessed
+    /// document by default.
+    pub output_dir: Option<PathBuf>,
+    /// Arguments passed to `typst compile`, unless others are given on the
+    /// command line.
+    #[serde(default)]
+    pub typst_args: Vec<String>,
+    /// Chunk options applied to every chunk of a language that does not
+    /// set them itself, keyed by language, as the text of option values.
+    #[serde(default, deserialize_with = "deserialize_defaults")]
+    pub defaults: HashMap<String, BTreeMap<String, String>>,
+    /// The interpreters run by the built-in languages.
+    #[serde(default)]
+    pub interpreters: Interpreters,
+    /// Interpreters driven through the generic REPL backend, keyed by the
+    /// language name used on code chunks.
+    #[serde(default)]
+    pub repl: HashMap<String, ReplSpec>,
+    /// Whether chunk results are cached between runs.
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+    /// Where cached chunk results are kept.
+    ///
+    /// Loading a project always sets it, to `.typstpp-cache` in the project
+    /// directory unless `typstpp.toml` names another. It is only unset in
+    /// a `Config::default()`, which does not cache.
+    pub cache_dir: Option<PathBuf>,
+    /// How long a chunk may run, unless it sets its own `timeout`.
+    #[serde(default, deserialize_with = "deserialize_timeout")]
+    pub timeout: Option<Duration>,
+    /// What to do when a chunk fails.
+    #[serde(default)]
+    pub failure: FailurePolicy,
+    /// How images produced by chunks are put into the document.
+    #[serde(default)]
+    pub graphics: GraphicsMode,
+    /// Where image files are written, relative to the project directory.
+    #[serde(default = "default_figure_dir")]
+    pub figure_dir: PathBuf,
+    /// A Typst file whose contents follow the built-in prelude in every
+    /// preprocessed file.
+    pub prelude: Option<PathBuf>,
+}
+
+/// The interpreters of the built-in languages.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Interpreters {
+    #[serde(default)]
+    pub r: Interpreter,
+    #[serde(default)]
+    pub py: Interpreter,
+    #[serde(default)]
+    pub hs: Interpreter,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Interpreter {
+    /// The program to run, a path or a name looked up in `PATH`.
+    pub command: Option<String>,
+    /// Extra arguments passed to it.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GraphicsMode {
+    /// Write each image to a file in the figure directory and load it from
+    /// there.
+    #[default]
+    Files,
+    /// Embed the images in the document, so it stands on its own.
+    Embed,
+}
+
+fn default_figure_dir() -> PathBuf {
+    PathBuf::from("figures")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailurePolicy {
+    /// Render errors in place of the failing chunks' output and carry on.
+    #[default]
+    KeepGoing,
+    /// Fail the run if any chunk fails, unless it is marked `#| error: true`.
+    Strict,
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+fn deserialize_timeout<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_duration(&s)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration `{}`", s)))
+}
+
+fn deserialize_defaults<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<HashMap<String, BTreeMap<String, String>>, D::Error> {
+    let tables = HashMap::<String, BTreeMap<String, toml::Value>>::deserialize(d)?;
+    tables
+        .into_iter()
+        .map(|(lang, table)| {
+            let options = table
+                .into_iter()
+                .map(|(name, value)| {
+                    let text = option_text(&value).ok_or_else(|| {
+                        serde::de::Error::custom(format!(
+                            "unsupported value for option `{}` of `{}`",
+                            name, lang
+                        ))
+                    })?;
+                    // Knitr spellings are read the Quarto way, as in chunks.
+                    Ok((name.replace('.', "-"), text))
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((lang, options))
+        })
+        .collect()
+}
+
+/// A TOML value written the way it would be on a `#|` line.
+fn option_text(value: &toml::Value) -> Option<String> {
+    Some(match value {
+        toml::Value::String(s) if s.starts_with("!expr ") => s.clone(),
+        toml::Value::String(s) => format!(
+            "\"{}\"",
+            s.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\t', "\\t")
+        ),
+        toml::Value::Integer(n) => n.to_string(),
+        toml::Value::Float(n) => n.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(option_text)
+                .collect::<Option<Vec<_>>>()?
+                .join(", ")
+        ),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => return None,
+    })
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            root: PathBuf::new(),
+            entries: Vec::new(),
+            output_dir: None,
+            typst_args: Vec::new(),
+            defaults: HashMap::new(),
+            interpreters: Interpreters::default(),
+            repl: HashMap::new(),
+            cache: default_cache(),
+            cache_dir: None,
+            timeout: None,
+            failure: FailurePolicy::default(),
+            graphics: GraphicsMode::default(),
+            figure_dir: default_figure_dir(),
+            prelude: None,
+        }
+    }
+}
+
+impl Config {
+    pub const FILE_NAME: &'static str = "typstpp.toml";
+
+    /// Load the configuration of the project `input` belongs to.
+    pub async fn load_for<P: AsRef<Path>>(input: P) -> Result<Self, Error> {
+        Self::discover(input.as_ref().parent().unwrap_or(Path::new(""))).await
+    }
+
+    /// Load the nearest configuration in `dir` or the directories above
+    /// it, or the defaults for `dir` if there is none.
+    pub async fn discover(dir: &Path) -> Result<Self, Error> {
+        let absolute = std::env::current_dir()?.join(dir);
+        for ancestor in absolute.ancestors() {
+            if tokio::fs::try_exists(ancestor.join(Self::FILE_NAME)).await? {
+                // Paths stay relative when the file is right there.
+                let root = if ancestor == absolute { dir } else { ancestor };
+                return Self::load_in(root).await;
+            }
+        }
+        Self::load_in(dir).await
+    }
+
+    /// Load the configuration in `dir`, or the defaults if there is none.
+    pub async fn load_in(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(Self::FILE_NAME);
+        let mut config: Config = match tokio::fs::read_to_string(&path).await {
+            Ok(s) => toml::from_str(&s)
+                .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.cache_dir = Some(
+            dir.join(
+                config
+                    .cache_dir
+                    .take()
+                    .unwrap_or_else(|| PathBuf::from(".typstpp-cache")),
+            ),
+        );
+        config.entries = config.entries.iter().map(|e| dir.join(e)).collect();
+        config.output_dir = config.output_dir.map(|d| dir.join(d));
+        config.prelude = config.prelude.map(|p| dir.join(p));
+        config.figure_dir = dir.join(&config.figure_dir);
+        for interpreter in [
+            &mut config.interpreters.r,
+            &mut config.interpreters.py,
+            &mut config.interpreters.hs,
+        ] {
+            if let Some(command) = &mut interpreter.command {
+                resolve_command(dir, command);
+            }
+        }
+        for spec in config.repl.values_mut() {
+            resolve_command(dir, &mut spec.command);
+        }
+        config.root = dir.to_path_buf();
+        Ok(config)
+    }
+}
+
+/// Resolve a command from `typstpp.toml`: bare names are looked up in
+/// `PATH`, other paths are relative to the project.
+fn resolve_command(dir: &Path, command: &mut String) {
+    if command.contains(['/', std::path::MAIN_SEPARATOR]) {
+        *command = dir.join(&*command).to_string_lossy().into_owned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use typstpp_backend::OptionValue;
+
+    #[test]
+    fn test_defaults() {
+        let config: Config = toml::from_str(concat!(
+            "[defaults.r]\n",
+            "echo = false\n",
+            "\"fig.width\" = 6\n",
+            "fig-cap = [\"A \\\"quoted\\\" caption\", 'C:\\dir']\n",
+            "eval = \"!expr nrow(iris) > 100\"\n",
+        ))
+        .unwrap();
+        let r = &config.defaults["r"];
+        assert_eq!(r["echo"], "false");
+        assert_eq!(r["fig-width"], "6");
+        assert_eq!(r["fig-cap"], r#"["A \"quoted\" caption", "C:\\dir"]"#);
+        assert_eq!(r["eval"], "!expr nrow(iris) > 100");
+        assert_eq!(
+            OptionValue::parse(&r["fig-cap"]),
+            Ok(OptionValue::List(vec![
+                OptionValue::String("A \"quoted\" caption".to_string()),
+                OptionValue::String("C:\\dir".to_string()),
+            ]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_in() {
+        let dir = std::env::temp_dir().join(format!("typstpp-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(Config::FILE_NAME),
+            concat!(
+                "figure-dir = \"build/figures\"\n",
+                "[repl.local]\n",
+                "command = \"./tools/repl\"\n",
+                "cookie = \"echo {cookie}\"\n",
+                "[repl.bash]\n",
+                "command = \"bash\"\n",
+                "cookie = \"echo {cookie}\"\n",
+            ),
+        )
+        .unwrap();
+        let config = Config::load_in(&dir).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.figure_dir, dir.join("build/figures"));
+        assert_eq!(
+            Path::new(&config.repl["local"].command),
+            dir.join("./tools/repl")
+        );
+        assert_eq!(config.repl["bash"].command, "bash");
+    }
+}
@@ -1,132 +1,1450 @@
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+};
 
-use crate::source::{Chunk, CodeChunk};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use typst_syntax::{ast, LinkedNode, SyntaxKind};
+use typstpp_backend::{
+    is_listing_label, split_list, typst_string, Figure, OptionValue, Output, OutputType,
+};
+
+use crate::{
+    config::GraphicsMode,
+    map::SourceMap,
+    source::{
+        Chunk, CodeChunk, ExecutedChunk, GraphicsType, IncludeChunk, RawOption, SourceChunk, Span,
+    },
+    Diagnostic, Error,
+};
 
 pub trait InputFile {
-    async fn read_chunk(&mut self) -> Result<Option<Chunk>, tokio::io::Error>;
+    async fn read_chunk(&mut self) -> Result<Option<SourceChunk>, Error>;
 }
 
+/// Where the executed document is written, one chunk at a time.
+#[allow(async_fn_in_trait)]
 pub trait OutputFile {
-    async fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), tokio::io::Error>;
+    /// Write what comes before the first chunk.
+    async fn start(&mut self) -> Result<(), tokio::io::Error>;
+    /// Write a chunk of the document along with what running it produced.
+    async fn write_chunk(&mut self, chunk: &ExecutedChunk) -> Result<(), tokio::io::Error>;
+    /// Write what comes after the last chunk.
+    async fn finish(&mut self) -> Result<(), tokio::io::Error>;
 }
 
 pub struct InputTypstFile<R: AsyncRead + Unpin> {
-    buffer: BufReader<R>,
+    /// The document, until it has been read and parsed on the first call to
+    /// `read_chunk`.
+    reader: Option<R>,
+    path: PathBuf,
+    pending: VecDeque<SourceChunk>,
+    includes: Vec<PathBuf>,
 }
 
 impl<R: AsyncRead + Unpin> InputTypstFile<R> {
-    pub fn new(reader: R) -> Self {
+    pub fn new<P: Into<PathBuf>>(reader: R, path: P) -> Self {
         InputTypstFile {
-            buffer: BufReader::new(reader),
+            reader: Some(reader),
+            path: path.into(),
+            pending: VecDeque::new(),
+            includes: Vec::new(),
         }
     }
+
+    /// The files the document includes or imports, known once it has been
+    /// read.
+    pub fn includes(&self) -> &[PathBuf] {
+        &self.includes
+    }
 }
 
 impl<R: AsyncRead + Unpin> InputFile for InputTypstFile<R> {
-    async fn read_chunk(&mut self) -> Result<Option<Chunk>, tokio::io::Error> {
-        let mut line = String::new();
-        let mut code = String::new();
-        let mut options = Vec::new();
-
-        let r = self.buffer.read_line(&mut line).await?;
-        if r == 0 {
-            return Ok(None);
-        }
-
-        if line.trim().starts_with("```") {
-            let lang = line.trim().strip_prefix("```").unwrap().trim();
-            // read options
-            let mut reading_options = true;
-            loop {
-                let mut line = String::new();
-                let r = self.buffer.read_line(&mut line).await?;
-                if r == 0 {
-                    return Err(tokio::io::Error::other("unexpected EOF"));
-                }
-                if reading_options && line.trim().starts_with("#|") {
-                    let kv = line
-                        .trim()
-                        .strip_prefix("#|")
-                        .unwrap()
-                        .splitn(2, ':')
-                        .map(str::trim)
-                        .collect::<Vec<_>>();
-                    options.push((kv[0].to_string(), kv[1].to_string()));
-                } else {
-                    reading_options = false;
-                    if line.trim().starts_with("```") {
-                        break;
-                    }
-                    code.push_str(&line);
+    async fn read_chunk(&mut self) -> Result<Option<SourceChunk>, Error> {
+        if let Some(mut reader) = self.reader.take() {
+            let mut text = String::new();
+            reader.read_to_string(&mut text).await?;
+            let (chunks, includes) = parse_document(&text, &self.path)?;
+            let root = self.path.parent().unwrap_or(Path::new(""));
+            self.pending = chunks.into();
+            self.includes = includes
+                .iter()
+                .map(|p| resolve_include(root, &self.path, p))
+                .collect();
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+/// Split a document into verbatim text and code chunks.
+///
+/// The document is parsed as Typst, so only raw text in markup is
+/// considered: fences inside comments, strings, code expressions or longer
+/// fences are left alone. Raw blocks with a knitr-style header, e.g.
+/// ```` ```{r label, echo=FALSE} ````, become code chunks, and so does inline
+/// raw text with such a header or single-backtick raw text whose content
+/// starts with a language name followed by a space, e.g. `` `r mean(x)` ``.
+/// Raw blocks with a plain language tag are only displayed. Everything else
+/// is kept byte for byte.
+///
+/// Also returns the paths of the files the document includes or imports, as
+/// written; local Typst files among them are also `Include` chunks.
+pub fn parse_document(text: &str, path: &Path) -> Result<(Vec<SourceChunk>, Vec<String>), Error> {
+    let root = typst_syntax::parse(text);
+    let mut parser = DocumentParser {
+        text,
+        path,
+        line_starts: std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect(),
+        chunks: Vec::new(),
+        verbatim_start: 0,
+        includes: Vec::new(),
+    };
+    parser.visit(&LinkedNode::new(&root))?;
+    parser.push_verbatim(text.len());
+    Ok((parser.chunks, parser.includes))
+}
+
+/// Where Typst finds a file included or imported as `path` from `file`:
+/// relative to `file`, or to the project `root` if it starts with `/`.
+pub fn resolve_include(root: &Path, file: &Path, path: &str) -> PathBuf {
+    match path.strip_prefix('/') {
+        Some(path) => root.join(path),
+        None => file.parent().unwrap_or(Path::new("")).join(path),
+    }
+}
+
+/// Where the preprocessed copy of the Typst file at `path` is written,
+/// next to the original so that its relative paths still work.
+pub fn preprocessed_path(path: &str) -> String {
+    format!("{}.out.typ", path.strip_suffix(".typ").unwrap_or(path))
+}
+
+/// The string literal giving the path of a file included or imported;
+/// package imports and computed paths are left alone.
+fn module_path<'a>(node: &LinkedNode<'a>) -> Option<(String, LinkedNode<'a>)> {
+    if !matches!(
+        node.kind(),
+        SyntaxKind::ModuleInclude | SyntaxKind::ModuleImport
+    ) {
+        return None;
+    }
+    let literal = node.children().find(|c| c.kind() == SyntaxKind::Str)?;
+    let path = literal.cast::<ast::Str>()?.get().to_string();
+    (!path.starts_with('@')).then_some((path, literal))
+}
+
+struct DocumentParser<'a> {
+    text: &'a str,
+    path: &'a Path,
+    /// Byte offsets at which each line starts.
+    line_starts: Vec<usize>,
+    chunks: Vec<SourceChunk>,
+    /// Where the verbatim text not yet pushed as a chunk starts.
+    verbatim_start: usize,
+    /// The files included or imported, as written.
+    includes: Vec<String>,
+}
+
+impl DocumentParser<'_> {
+    fn span(&self, start: usize, end: usize) -> Span {
+        let line = |offset: usize| self.line_starts.partition_point(|&s| s <= offset);
+        let start_line = line(start);
+        let line_start = self.line_starts[start_line - 1];
+        Span {
+            file: self.path.to_path_buf(),
+            start_line,
+            end_line: line(end.saturating_sub(1).max(start)),
+            column: self.text[line_start..start].chars().count() + 1,
+        }
+    }
+
+    fn push_verbatim(&mut self, end: usize) {
+        if end > self.verbatim_start {
+            self.chunks.push(SourceChunk {
+                chunk: Chunk::Verbatim(self.text[self.verbatim_start..end].to_string()),
+                span: self.span(self.verbatim_start, end),
+                indent: String::new(),
+            });
+        }
+    }
+
+    /// Replace `start..end` of the document with `chunk`, which begins
+    /// after `indent`.
+    fn push_chunk(&mut self, start: usize, end: usize, chunk: Chunk, indent: &str) {
+        self.push_verbatim(start);
+        self.chunks.push(SourceChunk {
+            chunk,
+            span: self.span(start + indent.len(), end),
+            indent: indent.to_string(),
+        });
+        self.verbatim_start = end;
+    }
+
+    fn visit(&mut self, node: &LinkedNode) -> Result<(), Error> {
+        if let Some((path, literal)) = module_path(node) {
+            // Typst files are preprocessed too, and the path replaced with
+            // that of their preprocessed copy.
+            if path.ends_with(".typ") {
+                let range = literal.range();
+                self.push_chunk(
+                    range.start,
+                    range.end,
+                    Chunk::Include(IncludeChunk {
+                        path: path.clone(),
+                        literal: literal.text().to_string(),
+                    }),
+                    "",
+                );
+            }
+            self.includes.push(path);
+        }
+        match node.kind() {
+            SyntaxKind::Raw if node.parent_kind() == Some(SyntaxKind::Markup) => {
+                self.raw(node);
+                Ok(())
+            }
+            // Unterminated raw text is lexed as a single error node.
+            SyntaxKind::Error if node.text().starts_with('`') => {
+                let range = node.range();
+                Err(Error::Parse(Diagnostic {
+                    span: self.span(range.start, range.end),
+                    message: node
+                        .get()
+                        .errors()
+                        .first()
+                        .map(|e| e.message.to_string())
+                        .unwrap_or_else(|| "unclosed raw text".to_string()),
+                    allowed: false,
+                }))
+            }
+            _ => {
+                for child in node.children() {
+                    self.visit(&child)?;
                 }
+                Ok(())
+            }
+        }
+    }
+
+    fn raw(&mut self, node: &LinkedNode) {
+        let raw: ast::Raw = node.cast().unwrap();
+        // Raw text with a language tag is only displayed; executable chunks
+        // are written ```{r} or `r ...`.
+        if raw.lang().is_some() {
+            return;
+        }
+        let range = node.range();
+        let fence = node.children().next().map_or(0, |d| d.text().len());
+        let lines = raw.lines().map(|l| l.get().as_str()).collect::<Vec<_>>();
+        let multiline =
+            node.text().contains('\n') || node.children().any(|c| c.text().contains('\n'));
+        let Some((first, rest)) = lines.split_first() else {
+            return;
+        };
+        let (lang, mut options, first) = match parse_header(first) {
+            Some(header) => header,
+            None if fence == 1 && !multiline => {
+                let Some((lang, code)) =
+                    first.split_once(' ').filter(|(lang, _)| is_language(lang))
+                else {
+                    return;
+                };
+                (lang.to_string(), Vec::new(), code)
+            }
+            None => return,
+        };
+        if !multiline {
+            if first.trim().is_empty() {
+                return;
             }
-            Ok(Some(Chunk::Code(CodeChunk {
-                lang: lang.into(),
-                options: options.into_iter().collect(),
+            self.push_chunk(
+                range.start,
+                range.end,
+                Chunk::Inline(CodeChunk {
+                    lang,
+                    options,
+                    code: first.trim().to_string(),
+                }),
+                "",
+            );
+            return;
+        }
+
+        let mut code_lines = rest.iter().peekable();
+        let mut option_lines = Vec::new();
+        while let Some(line) = code_lines.next_if(|l| l.trim_start().starts_with("#|")) {
+            option_lines.push(*line);
+        }
+        options.extend(parse_option_lines(&option_lines));
+        let code = (!first.trim().is_empty())
+            .then_some(first)
+            .into_iter()
+            .chain(code_lines.copied())
+            .map(|l| format!("{}\n", l))
+            .collect::<String>();
+
+        // A block standing on lines of its own takes those lines with it,
+        // and its output is indented like it.
+        let line_start = self.text[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let before = &self.text[line_start..range.start];
+        let after = &self.text[range.end..];
+        let after = &after[..after.find('\n').map_or(after.len(), |i| i + 1)];
+        let (start, end, indent) = if before.trim().is_empty() && after.trim().is_empty() {
+            (line_start, range.end + after.len(), before)
+        } else {
+            (range.start, range.end, "")
+        };
+        self.push_chunk(
+            start,
+            end,
+            Chunk::Code(CodeChunk {
+                lang,
+                options,
                 code,
-            })))
+            }),
+            indent,
+        );
+    }
+}
+
+fn is_language(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Option names are written the Quarto way, so `fig.cap` is read as
+/// `fig-cap`.
+fn option_name(name: &str) -> String {
+    name.trim().replace('.', "-")
+}
+
+fn quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Parse a knitr-style chunk header such as `{r label, echo=FALSE}` into
+/// the chunk's language, its options and what follows the header on the
+/// same line. Values are R literals, translated to the `#|` syntax; any
+/// other value is kept as an expression.
+fn parse_header(line: &str) -> Option<(String, Vec<RawOption>, &str)> {
+    let header = line.strip_prefix('{')?;
+    let end = header.find('}')?;
+    let (header, rest) = (&header[..end], &header[end + 1..]);
+    let split = header.find([' ', ',']).unwrap_or(header.len());
+    let (lang, header) = header.split_at(split);
+    if !is_language(lang) {
+        return None;
+    }
+    let mut options = Vec::new();
+    for (i, part) in split_list(header).into_iter().enumerate() {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        let (name, value) = match part.split_once('=') {
+            Some((name, value)) => (option_name(name), header_value(value.trim())),
+            // A leading bare word is the chunk's label.
+            None if i == 0 => ("label".to_string(), part.to_string()),
+            None => (option_name(part), String::new()),
+        };
+        options.push(RawOption {
+            name,
+            value,
+            line: part.to_string(),
+        });
+    }
+    Some((lang.to_string(), options, rest))
+}
+
+fn header_value(value: &str) -> String {
+    match value {
+        "TRUE" | "T" => return "true".to_string(),
+        "FALSE" | "F" => return "false".to_string(),
+        _ => {}
+    }
+    if let Some(items) = value.strip_prefix("c(").and_then(|v| v.strip_suffix(')')) {
+        let items = split_list(items)
+            .into_iter()
+            .map(|i| header_value(i.trim()))
+            .collect::<Vec<_>>();
+        return format!("[{}]", items.join(", "));
+    }
+    match OptionValue::parse(value) {
+        Ok(OptionValue::String(_)) if !value.starts_with(['"', '\'']) => {
+            format!("!expr {}", value)
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Parse the `#|` lines of a chunk. Values may continue on indented lines
+/// the way YAML allows: `|` keeps the line breaks, `>` folds them into
+/// spaces, `- item` lines make a list and anything else is folded.
+fn parse_option_lines(lines: &[&str]) -> Vec<RawOption> {
+    let mut groups: Vec<Vec<&str>> = Vec::new();
+    for line in lines {
+        let content = line.trim_start()[2..]
+            .strip_prefix(' ')
+            .unwrap_or(&line.trim_start()[2..]);
+        match groups.last_mut() {
+            Some(group) if content.trim().is_empty() || content.starts_with([' ', '\t', '-']) => {
+                group.push(line)
+            }
+            _ => groups.push(vec![line]),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|group| {
+            let option = group[0].trim_start()[2..].trim();
+            // A missing colon leaves the value empty, which is reported when
+            // the options are checked.
+            let (name, value) = option.split_once(':').unwrap_or((option, ""));
+            let continued = group[1..]
+                .iter()
+                .map(|l| l.trim_start()[2..].trim_end())
+                .collect::<Vec<_>>();
+            RawOption {
+                name: option_name(name),
+                value: yaml_value(value.trim(), &continued),
+                line: group
+                    .iter()
+                    .map(|l| l.trim())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            }
+        })
+        .collect()
+}
+
+fn yaml_value(value: &str, continued: &[&str]) -> String {
+    if continued.iter().all(|l| l.trim().is_empty()) {
+        return value.to_string();
+    }
+    let indent = continued
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines = continued
+        .iter()
+        .map(|l| l.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>();
+    let keep = |text: String, chomp: &str| match chomp {
+        "-" => text.trim_end_matches('\n').to_string(),
+        _ => format!("{}\n", text.trim_end_matches('\n')),
+    };
+    if let Some(chomp) = value.strip_prefix('|') {
+        return quote(&keep(lines.join("\n"), chomp));
+    }
+    if let Some(chomp) = value.strip_prefix('>') {
+        return quote(&keep(fold(&lines), chomp));
+    }
+    if value.is_empty() && lines.iter().all(|l| l.is_empty() || l.starts_with('-')) {
+        let items = lines
+            .iter()
+            .filter_map(|l| l.strip_prefix('-'))
+            .map(|item| {
+                let item = item.trim();
+                if item.starts_with(['"', '\'']) || !item.contains([',', '[', ']']) {
+                    item.to_string()
+                } else {
+                    quote(item)
+                }
+            })
+            .collect::<Vec<_>>();
+        return format!("[{}]", items.join(", "));
+    }
+    fold(&std::iter::once(value).chain(lines).collect::<Vec<_>>())
+        .trim()
+        .to_string()
+}
+
+/// Join lines with spaces, keeping blank lines as line breaks.
+fn fold(lines: &[&str]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            out.push('\n');
         } else {
-            Ok(Some(Chunk::Verbatim(line)))
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push(' ');
+            }
+            out.push_str(line);
         }
     }
+    out
+}
+
+/// Render `text` as a raw block, with a fence longer than any run of
+/// backticks inside it.
+fn raw_block(lang: &str, text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!(
+        "{}{}\n{}\n{}\n",
+        fence,
+        lang,
+        text.trim_end_matches('\n'),
+        fence
+    )
+}
+
+fn option_text(value: &OptionValue) -> String {
+    match value {
+        OptionValue::String(s) => s.clone(),
+        v => v.to_string(),
+    }
 }
 
-pub struct OutputTypstFile<W: tokio::io::AsyncWrite + Unpin> {
+/// Lines picked by `code-highlight`, written as numbers and ranges such as
+/// `2-4`, separated by commas or given as a list.
+fn highlighted_lines(value: &OptionValue) -> Vec<usize> {
+    let items = match value {
+        OptionValue::List(items) => items.iter().map(option_text).collect(),
+        v => vec![option_text(v)],
+    };
+    items
+        .iter()
+        .flat_map(|i| i.split(','))
+        .filter_map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            Some(start.trim().parse::<usize>().ok()?..=end.trim().parse::<usize>().ok()?)
+        })
+        .flatten()
+        .collect()
+}
+
+/// The arguments of `src` from `prelude.typ` for the echoed source of a
+/// chunk, and the label of the listing, if any.
+fn listing(code: &CodeChunk) -> (Vec<String>, Option<String>) {
+    let label = code
+        .option("label")
+        .map(|l| option_text(&l))
+        .filter(|l| is_listing_label(l));
+    let caption = code.option("lst-cap").map(|c| option_text(&c));
+    let mut args = Vec::new();
+    if label.is_some() || caption.is_some() {
+        args.push("listing: true".to_string());
+    }
+    if let Some(caption) = caption {
+        args.push(format!("caption: [{}]", caption));
+    }
+    if code.option("code-line-numbers").and_then(|v| v.as_bool()) == Some(true) {
+        args.push("line-numbers: true".to_string());
+    }
+    if let Some(lines) = code.option("code-highlight").map(|v| highlighted_lines(&v)) {
+        let lines = lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        args.push(format!("highlight: ({},)", lines.join(", ")));
+    }
+    (args, label)
+}
+
+/// Write an image into `dir`, named after its content so that unchanged
+/// plots keep their file across runs.
+async fn write_figure(
+    dir: &Path,
+    ty: GraphicsType,
+    data: &[u8],
+) -> Result<PathBuf, tokio::io::Error> {
+    let hash = Sha256::digest(data)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let path = dir.join(format!("typstpp-{}.{}", hash, ty.extension()));
+    if !fs::try_exists(&path).await? {
+        fs::create_dir_all(dir).await?;
+        fs::write(&path, data).await?;
+    }
+    Ok(path)
+}
+
+/// The path of `path` relative to the directory `base`, both of which exist.
+async fn relative_path(path: &Path, base: &Path) -> Result<PathBuf, tokio::io::Error> {
+    let path = fs::canonicalize(path).await?;
+    let base = match base.as_os_str().is_empty() {
+        true => fs::canonicalize(".").await?,
+        false => fs::canonicalize(base).await?,
+    };
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    Ok(relative)
+}
+
+pub struct OutputTypstFile<W: AsyncWrite + Unpin> {
     writer: W,
+    indent: String,
+    at_line_start: bool,
+    graphics: GraphicsMode,
+    figure_dir: PathBuf,
+    /// The source line of each output line started so far.
+    lines: Vec<usize>,
+    /// The number of output lines finished so far.
+    newlines: usize,
+    /// The source line of what is being written, and whether it moves on
+    /// with each line, as verbatim text does.
+    origin: usize,
+    advance: bool,
+    /// The document being written, known from its first chunk.
+    source: Option<PathBuf>,
+    /// The preprocessed copies of the files the document includes, by the
+    /// path of the original.
+    includes: HashMap<PathBuf, OutputTypstFile<fs::File>>,
+    /// The directory the output is written to, which Typst resolves the
+    /// paths of figures against. Without it, they are left as they are.
+    dir: Option<PathBuf>,
+    /// What is written before the first chunk.
+    prelude: String,
 }
 
-impl<W: tokio::io::AsyncWrite + Unpin> OutputTypstFile<W> {
+impl<W: AsyncWrite + Unpin> OutputTypstFile<W> {
     pub fn new(writer: W) -> Self {
-        OutputTypstFile { writer }
+        OutputTypstFile {
+            writer,
+            indent: String::new(),
+            at_line_start: true,
+            graphics: GraphicsMode::default(),
+            figure_dir: PathBuf::from("figures"),
+            lines: Vec::new(),
+            newlines: 0,
+            origin: 0,
+            advance: false,
+            source: None,
+            includes: HashMap::new(),
+            dir: None,
+            prelude: include_str!("prelude.typ").to_string(),
+        }
+    }
+
+    /// Where each line written so far came from in `source`.
+    pub fn source_map(&self, source: &Path) -> SourceMap {
+        SourceMap {
+            source: source.to_path_buf(),
+            lines: self.lines.clone(),
+        }
+    }
+
+    /// Put images into the document as `mode` says, writing files into
+    /// `figure_dir` if need be.
+    pub fn with_graphics<P: Into<PathBuf>>(mut self, mode: GraphicsMode, figure_dir: P) -> Self {
+        self.graphics = mode;
+        self.figure_dir = figure_dir.into();
+        self
+    }
+
+    /// Give figure paths relative to `path`, where the output is written.
+    pub fn with_output_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.dir = Some(path.as_ref().parent().unwrap_or(Path::new("")).into());
+        self
+    }
+
+    /// Write `extra` after the built-in prelude, so that a project can add
+    /// definitions of its own or replace those of the prelude.
+    pub fn with_prelude(mut self, extra: &str) -> Self {
+        if !self.prelude.ends_with('\n') {
+            self.prelude.push('\n');
+        }
+        self.prelude.push_str(extra);
+        self
+    }
+
+    /// The start of the Typst call loading an image, without its closing
+    /// parenthesis.
+    async fn image(&self, ty: GraphicsType, data: &[u8]) -> Result<String, tokio::io::Error> {
+        Ok(match (self.graphics, ty) {
+            (GraphicsMode::Files, ty) => {
+                let mut path = write_figure(&self.figure_dir, ty, data).await?;
+                if let Some(dir) = &self.dir {
+                    path = relative_path(&path, dir).await?;
+                }
+                let path = path.to_string_lossy().replace('\\', "/");
+                format!("image({}", typst_string(&path))
+            }
+            (GraphicsMode::Embed, GraphicsType::Svg) => format!(
+                "image.decode({}, format: \"svg\"",
+                typst_string(&String::from_utf8_lossy(data)).replace('\n', "\\n")
+            ),
+            (GraphicsMode::Embed, ty) => format!(
+                "image.decode(bytes(({},)), format: \"{}\"",
+                data.iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                ty.extension()
+            ),
+        })
+    }
+
+    /// Indent the lines of the chunks written from now on, so that output
+    /// replacing an indented code block stays inside its list item or
+    /// content block.
+    pub fn set_indent(&mut self, indent: &str) {
+        self.indent = indent.to_string();
+    }
+
+    async fn write_str(&mut self, s: &str) -> Result<(), tokio::io::Error> {
+        for line in s.split_inclusive('\n') {
+            if self.lines.len() == self.newlines {
+                self.lines.push(self.origin);
+            }
+            if line.ends_with('\n') {
+                self.newlines += 1;
+                if self.advance {
+                    self.origin += 1;
+                }
+            }
+        }
+        if self.indent.is_empty() {
+            self.writer.write_all(s.as_bytes()).await?;
+        } else {
+            let mut out = String::with_capacity(s.len());
+            for line in s.split_inclusive('\n') {
+                if self.at_line_start && line != "\n" {
+                    out.push_str(&self.indent);
+                }
+                out.push_str(line);
+                self.at_line_start = line.ends_with('\n');
+            }
+            self.writer.write_all(out.as_bytes()).await?;
+        }
+        if let Some(c) = s.chars().last() {
+            self.at_line_start = c == '\n';
+        }
+        Ok(())
+    }
+
+    async fn write_figure(
+        &mut self,
+        ty: GraphicsType,
+        data: &[u8],
+        figure: &Figure,
+    ) -> Result<(), tokio::io::Error> {
+        let image = self.image(ty, data).await?;
+        self.write_str(&figure.render(&image)).await
     }
-}
 
-impl<W: tokio::io::AsyncWrite + Unpin> OutputFile for OutputTypstFile<W> {
-    async fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), tokio::io::Error> {
+    /// Write one piece of the output document.
+    async fn write(&mut self, chunk: &Chunk) -> Result<(), tokio::io::Error> {
         match chunk {
             Chunk::Verbatim(s) => {
-                self.writer.write_all(s.as_bytes()).await?;
+                self.write_str(s).await?;
             }
-            Chunk::Code(CodeChunk {
-                lang,
-                options: _,
-                code,
-            }) => {
-                self.writer
-                    .write_all(format!("#src[\n```{}\n", lang).as_bytes())
-                    .await?;
-                self.writer.write_all(code.as_bytes()).await?;
-                self.writer.write_all("```\n]\n".as_bytes()).await?;
+            Chunk::Code(c) => {
+                let (args, label) = listing(c);
+                let open = if args.is_empty() {
+                    "#src[".to_string()
+                } else {
+                    format!("#src({})[", args.join(", "))
+                };
+                let label = label.map_or(String::new(), |l| format!(" <{}>", l));
+                self.write_str(&format!(
+                    "{}\n{}]{}\n",
+                    open,
+                    raw_block(&c.lang, &c.code),
+                    label
+                ))
+                .await?;
             }
             Chunk::Output(o) => {
-                self.writer
-                    .write_all(format!("```\n{}\n```\n", o.data).as_bytes())
+                self.write_str(&raw_block("", &o.data)).await?;
+            }
+            Chunk::Message(m) => {
+                self.write_str(&format!("#emoji.info\n{}", raw_block("", m)))
                     .await?;
             }
             Chunk::Error(e) => {
-                self.writer
-                    .write_all(format!("#emoji.crossmark {}\n", e).as_bytes())
+                self.write_str(&format!("#emoji.crossmark\n{}", raw_block("", e)))
+                    .await?;
+            }
+            Chunk::Graphics(g) => {
+                self.write_figure(g.ty, &g.data, &g.figure).await?;
+            }
+            Chunk::Include(i) => {
+                self.write_str(&typst_string(&preprocessed_path(&i.path)))
                     .await?;
             }
-            Chunk::Graphics(g) => match g.ty {
-                crate::source::GraphicsType::Png => {
-                    self.writer
-                        .write_all(
-                            format!(
-                                "#image.decode(bytes(({})))\n",
-                                g.data
-                                    .iter()
-                                    .map(|e| e.to_string())
-                                    .collect::<Vec<_>>()
-                                    .join(",")
-                            )
-                            .as_bytes(),
-                        )
-                        .await?;
-                }
-            },
             _ => unimplemented!("not implemented"),
         }
         Ok(())
     }
+
+    async fn write_error(
+        &mut self,
+        chunk: &ExecutedChunk,
+        message: &str,
+    ) -> Result<(), tokio::io::Error> {
+        let diagnostic = chunk.diagnostic(message);
+        self.write(&Chunk::Error(diagnostic.to_string())).await
+    }
+
+    async fn write_code_output(
+        &mut self,
+        c: &CodeChunk,
+        chunk: &ExecutedChunk,
+    ) -> Result<(), tokio::io::Error> {
+        for e in &chunk.result.errors {
+            self.write_error(chunk, e).await?;
+        }
+        for o in &chunk.result.outputs {
+            match &o.ty {
+                OutputType::Typst => self.write_str(&o.data).await?,
+                OutputType::Code => {
+                    self.write(&Chunk::Code(CodeChunk {
+                        lang: c.lang.clone(),
+                        options: c.options.clone(),
+                        code: o.data.clone(),
+                    }))
+                    .await?
+                }
+                OutputType::Output => self.write(&Chunk::Output(o.clone())).await?,
+                OutputType::Message => self.write(&Chunk::Message(o.data.clone())).await?,
+                OutputType::Error => self.write_error(chunk, &o.data).await?,
+                OutputType::Graphics(g) => self.write_figure(g.ty, &g.data, &g.figure).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Inline results are spliced into the surrounding markup as bare text.
+    async fn write_inline_output(&mut self, chunk: &ExecutedChunk) -> Result<(), tokio::io::Error> {
+        for e in &chunk.result.errors {
+            self.write_error(chunk, e).await?;
+        }
+        for o in &chunk.result.outputs {
+            match &o.ty {
+                OutputType::Typst | OutputType::Output => self.write_str(o.data.trim_end()).await?,
+                OutputType::Code => {}
+                OutputType::Message => self.write(&Chunk::Message(o.data.clone())).await?,
+                OutputType::Error => self.write_error(chunk, &o.data).await?,
+                OutputType::Graphics(g) => self.write_figure(g.ty, &g.data, &g.figure).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> OutputFile for OutputTypstFile<W> {
+    async fn start(&mut self) -> Result<(), tokio::io::Error> {
+        let prelude = std::mem::take(&mut self.prelude);
+        let result = self.write_str(&prelude).await;
+        self.prelude = prelude;
+        result
+    }
+
+    async fn write_chunk(&mut self, chunk: &ExecutedChunk) -> Result<(), tokio::io::Error> {
+        let file = &chunk.source.span.file;
+        if *file == *self.source.get_or_insert_with(|| file.clone()) {
+            return self.write_executed(chunk).await;
+        }
+        // Chunks of included files go to their preprocessed copies, which
+        // need the prelude of their own as Typst modules do not share
+        // definitions.
+        if !self.includes.contains_key(file) {
+            let path = preprocessed_path(&file.to_string_lossy());
+            let mut copy = OutputTypstFile::new(fs::File::create(&path).await?)
+                .with_graphics(self.graphics, self.figure_dir.clone());
+            copy.source = Some(file.clone());
+            copy.dir = Some(Path::new(&path).parent().unwrap_or(Path::new("")).into());
+            copy.prelude.clone_from(&self.prelude);
+            copy.start().await?;
+            self.includes.insert(file.clone(), copy);
+        }
+        self.includes
+            .get_mut(file)
+            .unwrap()
+            .write_executed(chunk)
+            .await
+    }
+
+    async fn finish(&mut self) -> Result<(), tokio::io::Error> {
+        for (source, copy) in self.includes.iter_mut() {
+            copy.writer.flush().await?;
+            let path = preprocessed_path(&source.to_string_lossy());
+            copy.source_map(source)
+                .write(&SourceMap::path_for(Path::new(&path)))
+                .await?;
+        }
+        self.writer.flush().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> OutputTypstFile<W> {
+    async fn write_executed(&mut self, chunk: &ExecutedChunk) -> Result<(), tokio::io::Error> {
+        // Verbatim text maps line by line, and a chunk's output to the
+        // line the chunk starts on.
+        self.origin = chunk.source.span.start_line;
+        self.advance = matches!(chunk.source.chunk, Chunk::Verbatim(_));
+        match &chunk.source.chunk {
+            Chunk::Code(c) => {
+                self.set_indent(&chunk.source.indent);
+                let result = self.write_code_output(c, chunk).await;
+                self.set_indent("");
+                result
+            }
+            Chunk::Inline(_) => self.write_inline_output(chunk).await,
+            c => self.write(c).await,
+        }
+    }
+}
+
+/// The executed document as JSON, for editors and other tools: an object
+/// whose `chunks` are the chunks of the document in order, one per line.
+///
+/// Images are written to files in the figure directory, and the `data` of
+/// an image output is the path of its file.
+pub struct OutputJsonFile<W: AsyncWrite + Unpin> {
+    writer: W,
+    figure_dir: PathBuf,
+    first: bool,
+}
+
+#[derive(Serialize)]
+struct JsonChunk<'a> {
+    #[serde(flatten)]
+    source: &'a SourceChunk,
+    outputs: Vec<Output<String>>,
+    errors: &'a [String],
+    elapsed_ms: Option<f64>,
+    cached: bool,
+}
+
+impl<W: AsyncWrite + Unpin> OutputJsonFile<W> {
+    pub fn new<P: Into<PathBuf>>(writer: W, figure_dir: P) -> Self {
+        OutputJsonFile {
+            writer,
+            figure_dir: figure_dir.into(),
+            first: true,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> OutputFile for OutputJsonFile<W> {
+    async fn start(&mut self) -> Result<(), tokio::io::Error> {
+        self.writer.write_all(b"{\"chunks\":[").await
+    }
+
+    async fn write_chunk(&mut self, chunk: &ExecutedChunk) -> Result<(), tokio::io::Error> {
+        let mut outputs = Vec::with_capacity(chunk.result.outputs.len());
+        for o in &chunk.result.outputs {
+            let data = match &o.ty {
+                OutputType::Graphics(g) => write_figure(&self.figure_dir, g.ty, &g.data)
+                    .await?
+                    .to_string_lossy()
+                    .replace('\\', "/"),
+                _ => o.data.clone(),
+            };
+            outputs.push(Output {
+                data,
+                ty: o.ty.clone(),
+            });
+        }
+        let record = JsonChunk {
+            source: &chunk.source,
+            outputs,
+            errors: &chunk.result.errors,
+            elapsed_ms: chunk.result.elapsed.map(|d| d.as_secs_f64() * 1000.0),
+            cached: chunk.result.cached,
+        };
+        let mut line = if self.first { "\n" } else { ",\n" }.as_bytes().to_vec();
+        serde_json::to_writer(&mut line, &record)?;
+        self.first = false;
+        self.writer.write_all(&line).await
+    }
+
+    async fn finish(&mut self) -> Result<(), tokio::io::Error> {
+        self.writer.write_all(b"\n]}\n").await?;
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{source::GraphicsChunk, CodeOutput};
+
+    fn describe(text: &str) -> Vec<String> {
+        parse_document(text, Path::new("doc.typ"))
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|c| match c.chunk {
+                Chunk::Verbatim(s) => format!("verbatim:{}", s),
+                Chunk::Inline(code) => {
+                    format!("inline@{}:{}:{}", c.span.column, code.lang, code.code)
+                }
+                Chunk::Code(code) => format!("code@{}:{}:{}", c.span, code.lang, code.code),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            describe("The mean is `r mean(x)`.\n"),
+            vec![
+                "verbatim:The mean is ",
+                "inline@13:r:mean(x)",
+                "verbatim:.\n"
+            ]
+        );
+        assert_eq!(
+            describe("`hs length xs` and ```{r} 1 + 1```"),
+            vec![
+                "inline@1:hs:length xs",
+                "verbatim: and ",
+                "inline@20:r:1 + 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plain_raw_is_verbatim() {
+        for text in [
+            "plain `raw` text\n",
+            "no code `r ` here\n",
+            "double ``r x`` ticks\n",
+            "escaped \\`r x\\` tick\n",
+            "// ```r\n// x\n// ```\n",
+            "#let s = \"```r\\nx\\n```\"\n",
+            "````\n```r\nx\n```\n````\n",
+            "```\nno language\n```\n",
+            "```r\nshown, not run\n```\n",
+            "```r x```\n",
+            "```{not a header\n```\n",
+        ] {
+            assert_eq!(describe(text), vec![format!("verbatim:{}", text)]);
+        }
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            describe("a\r\n```{r}\r\nx\r\n```\r\nb\r\n"),
+            vec!["verbatim:a\r\n", "code@doc.typ:2:1:r:x\n", "verbatim:b\r\n"]
+        );
+        assert_eq!(
+            describe("#box[\n  ````{py}\n  print(\"```\")\n  ````\n]\n"),
+            vec![
+                "verbatim:#box[\n",
+                "code@doc.typ:2:3:py:print(\"```\")\n",
+                "verbatim:]\n"
+            ]
+        );
+        assert_eq!(
+            describe("- item ```{r}\n  x\n  ``` after\n"),
+            vec![
+                "verbatim:- item ",
+                "code@doc.typ:1:8:r:x\n",
+                "verbatim: after\n"
+            ]
+        );
+    }
+
+    fn options(text: &str) -> Vec<(String, String)> {
+        match parse_document(text, Path::new("doc.typ"))
+            .unwrap()
+            .0
+            .remove(0)
+            .chunk
+        {
+            Chunk::Code(c) | Chunk::Inline(c) => {
+                c.options.into_iter().map(|o| (o.name, o.value)).collect()
+            }
+            _ => panic!("expected a code chunk"),
+        }
+    }
+
+    #[test]
+    fn test_header_options() {
+        assert_eq!(
+            options("```{r plot, echo=FALSE, fig.cap=\"A, b\", fig.width=7, out=c(\"a\", T), x=n * 2}\n#| eval: no\nplot(x)\n```\n"),
+            [
+                ("label", "plot"),
+                ("echo", "false"),
+                ("fig-cap", "\"A, b\""),
+                ("fig-width", "7"),
+                ("out", "[\"a\", true]"),
+                ("x", "!expr n * 2"),
+                ("eval", "no"),
+            ]
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+        );
+        assert_eq!(
+            options("`{r, eval=FALSE} 1 + 1`"),
+            [("eval".to_string(), "false".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_yaml_options() {
+        let opts = options(concat!(
+            "```{r}\n",
+            "#| fig-cap: |\n",
+            "#|   First line\n",
+            "#|   \"second\" line\n",
+            "#| fig.alt: >-\n",
+            "#|   folded\n",
+            "#|   text\n",
+            "#| fig-subcap:\n",
+            "#|   - A\n",
+            "#|   - \"B\"\n",
+            "#|   - c, d\n",
+            "#| echo: false\n",
+            "x\n",
+            "```\n",
+        ));
+        assert_eq!(
+            opts,
+            [
+                ("fig-cap", r#""First line\n\"second\" line\n""#),
+                ("fig-alt", r#""folded text""#),
+                ("fig-subcap", r#"[A, "B", "c, d"]"#),
+                ("echo", "false"),
+            ]
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+        );
+        assert_eq!(
+            OptionValue::parse(&opts[0].1).unwrap().as_str(),
+            Some("First line\n\"second\" line\n")
+        );
+    }
+
+    #[test]
+    fn test_unclosed_raw() {
+        let Err(Error::Parse(d)) = parse_document("unclosed `r x\n", Path::new("doc.typ")) else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(d.span.to_string(), "doc.typ:1:10");
+    }
+
+    #[tokio::test]
+    async fn test_indent_round_trip() {
+        let text = "- item\n  ```{r}\n  x\n  ```\n- next\n";
+        let mut out = OutputTypstFile::new(Vec::new());
+        for source in parse_document(text, Path::new("doc.typ")).unwrap().0 {
+            let result = CodeOutput {
+                outputs: source
+                    .chunk
+                    .code()
+                    .map(|c| Output {
+                        data: c.code.clone(),
+                        ty: OutputType::Code,
+                    })
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            };
+            out.write_chunk(&ExecutedChunk { source, result })
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out.writer).unwrap(),
+            "- item\n  #src[\n  ```r\n  x\n  ```\n  ]\n- next\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_map() {
+        let text = "= Title\n```{r}\nx\n```\nSee `r y`\nend\n";
+        let mut out = OutputTypstFile::new(Vec::new());
+        for source in parse_document(text, Path::new("doc.typ")).unwrap().0 {
+            let result = CodeOutput {
+                outputs: source
+                    .chunk
+                    .code()
+                    .map(|c| Output {
+                        data: c.code.clone(),
+                        ty: OutputType::Output,
+                    })
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            };
+            out.write_chunk(&ExecutedChunk { source, result })
+                .await
+                .unwrap();
+        }
+        let written = String::from_utf8(out.writer.clone()).unwrap();
+        assert_eq!(written, "= Title\n```\nx\n```\nSee y\nend\n");
+        let map = out.source_map(Path::new("doc.typ"));
+        assert_eq!(map.source, Path::new("doc.typ"));
+        assert_eq!(map.lines, vec![1, 2, 2, 2, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_listing() {
+        let text = concat!(
+            "```{r}\n",
+            "#| label: lst-mean\n",
+            "#| lst-cap: The *mean*\n",
+            "#| code-line-numbers: true\n",
+            "#| code-highlight: [1, 3-4]\n",
+            "mean(x)\n",
+            "```\n",
+        );
+        let mut out = OutputTypstFile::new(Vec::new());
+        for c in parse_document(text, Path::new("doc.typ")).unwrap().0 {
+            out.write(&c.chunk).await.unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out.writer).unwrap(),
+            concat!(
+                "#src(listing: true, caption: [The *mean*], line-numbers: true, highlight: (1, 3, 4,))[\n",
+                "```r\n",
+                "mean(x)\n",
+                "```\n",
+                "] <lst-mean>\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graphics() {
+        let svg = GraphicsChunk {
+            data: b"<svg>\n\"a\"</svg>".to_vec(),
+            ty: GraphicsType::Svg,
+            figure: Figure {
+                label: Some("fig-a".to_string()),
+                ..Default::default()
+            },
+        };
+        let png = GraphicsChunk {
+            data: vec![1, 2],
+            ty: GraphicsType::Png,
+            figure: Figure::default(),
+        };
+        let mut out = OutputTypstFile::new(Vec::new()).with_graphics(GraphicsMode::Embed, "unused");
+        out.write(&Chunk::Graphics(svg.clone())).await.unwrap();
+        out.write(&Chunk::Graphics(png)).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out.writer).unwrap(),
+            concat!(
+                "#figure(image.decode(\"<svg>\\n\\\"a\\\"</svg>\", format: \"svg\")) <fig-a>\n",
+                "#image.decode(bytes((1,2,)), format: \"png\")\n",
+            )
+        );
+
+        let dir = std::env::temp_dir().join(format!("typstpp-test-{}", std::process::id()));
+        let mut out = OutputTypstFile::new(Vec::new()).with_graphics(GraphicsMode::Files, &dir);
+        out.write(&Chunk::Graphics(svg.clone())).await.unwrap();
+        out.write(&Chunk::Graphics(svg)).await.unwrap();
+        let written = String::from_utf8(out.writer).unwrap();
+        let files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            written.matches(files[0].to_str().unwrap()).count(),
+            2,
+            "{}",
+            written
+        );
+    }
+
+    #[tokio::test]
+    async fn test_figure_paths() {
+        let dir = std::env::temp_dir().join(format!("typstpp-paths-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("chapters")).unwrap();
+        let png = GraphicsChunk {
+            data: vec![1, 2],
+            ty: GraphicsType::Png,
+            figure: Figure::default(),
+        };
+        let mut out = OutputTypstFile::new(Vec::new())
+            .with_graphics(GraphicsMode::Files, dir.join("figures"))
+            .with_output_path(dir.join("chapters/intro.out.typ"));
+        out.write(&Chunk::Graphics(png)).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let written = String::from_utf8(out.writer).unwrap();
+        assert!(
+            written.starts_with("#image(\"../figures/typstpp-"),
+            "{}",
+            written
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let text = "See `r x`.\n```{r}\n#| label: fig-x\nplot(x)\n```\n";
+        let mut results = vec![
+            CodeOutput::default(),
+            CodeOutput {
+                outputs: vec![Output {
+                    data: "1".to_string(),
+                    ty: OutputType::Output,
+                }],
+                elapsed: Some(std::time::Duration::from_millis(5)),
+                ..Default::default()
+            },
+            CodeOutput::default(),
+            CodeOutput {
+                errors: vec!["oops".to_string()],
+                outputs: vec![Output {
+                    data: String::new(),
+                    ty: OutputType::Graphics(typstpp_backend::Graphics {
+                        ty: GraphicsType::Svg,
+                        data: b"<svg/>".to_vec(),
+                        figure: Figure::default(),
+                    }),
+                }],
+                cached: true,
+                ..Default::default()
+            },
+        ]
+        .into_iter();
+        let dir = std::env::temp_dir().join(format!("typstpp-json-{}", std::process::id()));
+        let mut out = OutputJsonFile::new(Vec::new(), &dir);
+        out.start().await.unwrap();
+        for source in parse_document(text, Path::new("doc.typ")).unwrap().0 {
+            let result = results.next().unwrap();
+            out.write_chunk(&ExecutedChunk { source, result })
+                .await
+                .unwrap();
+        }
+        out.finish().await.unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, 1);
+
+        let written = String::from_utf8(out.writer).unwrap();
+        assert_eq!(written.lines().count(), 6, "{}", written);
+        let json: serde_json::Value = serde_json::from_str(&written).unwrap();
+        let chunks = json["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[0]["chunk"]["verbatim"], "See ");
+        assert_eq!(chunks[0]["elapsed_ms"], serde_json::Value::Null);
+        assert_eq!(chunks[1]["chunk"]["inline"]["code"], "x");
+        assert_eq!(chunks[1]["outputs"][0]["data"], "1");
+        assert_eq!(chunks[1]["outputs"][0]["ty"], "Output");
+        assert_eq!(chunks[1]["elapsed_ms"], 5.0);
+        let code = &chunks[3];
+        assert_eq!(code["span"]["start_line"], 2);
+        assert_eq!(code["chunk"]["code"]["lang"], "r");
+        assert_eq!(code["chunk"]["code"]["options"][0]["name"], "label");
+        assert_eq!(code["chunk"]["code"]["options"][0]["value"], "fig-x");
+        assert_eq!(code["errors"][0], "oops");
+        assert_eq!(code["cached"], true);
+        let path = code["outputs"][0]["data"].as_str().unwrap();
+        assert!(path.ends_with(".svg"), "{}", path);
+        assert_eq!(code["outputs"][0]["ty"]["Graphics"]["ty"], "Svg");
+        assert!(code["outputs"][0]["ty"]["Graphics"].get("data").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_includes() {
+        let text = concat!(
+            "#include \"intro.typ\"\n",
+            "#import \"/lib/util.typ\": f\n",
+            "#import \"@preview/cetz:0.2.2\"\n",
+            "#include name\n",
+            "// #include \"commented.typ\"\n",
+            "#[ #include \"nested.typ\" ]\n",
+        );
+        let mut input = InputTypstFile::new(text.as_bytes(), "doc/main.typ");
+        while input.read_chunk().await.unwrap().is_some() {}
+        assert_eq!(
+            input.includes(),
+            [
+                Path::new("doc/intro.typ"),
+                Path::new("doc/lib/util.typ"),
+                Path::new("doc/nested.typ"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_include_chunks() {
+        let text = "#include \"a.typ\"\n#import \"/lib.typ\": f\n#include \"data.csv\"\n";
+        let (chunks, _) = parse_document(text, Path::new("doc.typ")).unwrap();
+        let paths = chunks
+            .iter()
+            .filter_map(|c| match &c.chunk {
+                Chunk::Include(i) => Some(i.path.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a.typ", "/lib.typ"]);
+
+        let mut out = OutputTypstFile::new(Vec::new());
+        for source in chunks {
+            out.write_chunk(&ExecutedChunk {
+                source,
+                result: CodeOutput::default(),
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out.writer).unwrap(),
+            "#include \"a.out.typ\"\n#import \"/lib.out.typ\": f\n#include \"data.csv\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_options() {
+        let mut input = InputTypstFile::new(
+            "```{r}\n#| echo: no\n#| broken\nx\n```\n".as_bytes(),
+            "a.typ",
+        );
+        let Some(SourceChunk {
+            chunk: Chunk::Code(c),
+            span,
+            ..
+        }) = input.read_chunk().await.unwrap()
+        else {
+            panic!("expected a code chunk");
+        };
+        assert_eq!(c.options.len(), 2);
+        assert_eq!(c.option("echo").and_then(|v| v.as_bool()), Some(false));
+        assert_eq!(c.options[1].name, "broken");
+        assert_eq!(c.options[1].value, "");
+        assert_eq!(c.options[1].line, "#| broken");
+        assert_eq!(c.code, "x\n");
+        assert_eq!((span.start_line, span.end_line, span.column), (1, 5, 1));
+    }
+
+    #[tokio::test]
+    async fn test_spans() {
+        let mut input =
+            InputTypstFile::new("= Title\nSee `r x`.\n  ```r\nx\n".as_bytes(), "doc.typ");
+        let mut spans = vec![];
+        loop {
+            match input.read_chunk().await {
+                Ok(Some(c)) => spans.push(c.span.to_string()),
+                Ok(None) => break,
+                Err(e) => {
+                    spans.push(format!("{}", e));
+                    break;
+                }
+            }
+        }
+        assert_eq!(spans, vec!["doc.typ:3:3: unclosed raw text"]);
+    }
 }
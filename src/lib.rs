@@ -1,22 +1,183 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use crate::{io::InputFile, io::OutputFile};
-use source::CodeChunk;
-use tokio::{
-    fs,
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+use crate::io::InputFile;
+use cache::Cache;
+use config::{Config, GraphicsMode};
+use source::{Chunk, RawOption};
+use tokio::{fs, io::AsyncRead};
+use typstpp_backend::{
+    Backend, BackendInfo, ChunkOptions, GraphicsType, Input, OptionError, OptionSpec, OptionType,
+    OutputType,
 };
-use typstpp_backend::{Backend, Input};
+mod cache;
+pub mod config;
 mod io;
+mod map;
 mod source;
 
-#[derive(Debug)]
+pub use io::{OutputFile, OutputJsonFile, OutputTypstFile};
+pub use map::SourceMap;
+pub use source::{ExecutedChunk, Span};
+
+/// Chunk options handled by the driver itself, understood in every language.
+const DRIVER_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "label",
+        ty: OptionType::String,
+        doc: "A name for the chunk.",
+    },
+    OptionSpec {
+        name: "file",
+        ty: OptionType::String,
+        doc: "Read the chunk's code from this file instead.",
+    },
+    OptionSpec {
+        name: "depends",
+        ty: OptionType::List,
+        doc: "Files the chunk reads; changing them reruns it, and `watch` watches them.",
+    },
+    OptionSpec {
+        name: "cache",
+        ty: OptionType::Bool,
+        doc: "Reuse the chunk's result from earlier runs.",
+    },
+    OptionSpec {
+        name: "timeout",
+        ty: OptionType::Duration,
+        doc: "Stop the chunk if it runs longer than this.",
+    },
+    OptionSpec {
+        name: "error",
+        ty: OptionType::Bool,
+        doc: "Errors from the chunk are expected and do not fail the run.",
+    },
+    OptionSpec {
+        name: "output",
+        ty: OptionType::String,
+        doc: "`false` hides what the chunk prints, and `asis` writes it into the document as Typst markup.",
+    },
+    OptionSpec {
+        name: "lst-cap",
+        ty: OptionType::String,
+        doc: "Show the chunk's source as a listing with this caption, as Typst markup.",
+    },
+    OptionSpec {
+        name: "code-line-numbers",
+        ty: OptionType::Bool,
+        doc: "Number the lines of the chunk's source.",
+    },
+    OptionSpec {
+        name: "code-highlight",
+        ty: OptionType::List,
+        doc: "Lines of the chunk's source to highlight, such as `2-4` or `[1, 5-6]`.",
+    },
+];
+
+/// Chunk options understood in every language.
+pub fn common_options() -> &'static [OptionSpec] {
+    DRIVER_OPTIONS
+}
+
+/// A language typstpp can run, and what was found of its interpreter.
+pub struct LanguageInfo {
+    pub lang: String,
+    pub info: BackendInfo,
+    /// The options specific to this language.
+    pub options: &'static [OptionSpec],
+}
+
+/// Probe the interpreters of every language that is compiled in or
+/// configured, without starting them.
+pub async fn probe_languages(config: &Config) -> Vec<LanguageInfo> {
+    let mut languages = Vec::new();
+    #[cfg(feature = "r")]
+    languages.push(LanguageInfo {
+        lang: "r".to_string(),
+        info: typstpp_r::RBackend::probe(&r_options(config)).await,
+        options: typstpp_r::RBackend::option_schema(),
+    });
+    #[cfg(feature = "hs")]
+    languages.push(LanguageInfo {
+        lang: "hs".to_string(),
+        info: typstpp_hs::HsBackend::probe(&hs_options(config)).await,
+        options: typstpp_hs::HsBackend::option_schema(),
+    });
+    #[cfg(feature = "py")]
+    languages.push(LanguageInfo {
+        lang: "py".to_string(),
+        info: typstpp_py::PyBackend::probe(&py_options(config)).await,
+        options: typstpp_py::PyBackend::option_schema(),
+    });
+    let mut repl = config.repl.iter().collect::<Vec<_>>();
+    repl.sort_by_key(|(name, _)| name.as_str());
+    for (name, spec) in repl {
+        languages.push(LanguageInfo {
+            lang: name.clone(),
+            info: typstpp_repl::ReplBackend::probe(spec).await,
+            options: typstpp_repl::ReplBackend::option_schema(),
+        });
+    }
+    languages
+}
+
+#[cfg(feature = "r")]
+fn r_options(config: &Config) -> typstpp_r::RGlobalOptions {
+    typstpp_r::RGlobalOptions {
+        figure_path_prefix: Some(config.figure_dir.to_string_lossy().into_owned()),
+        command: config.interpreters.r.command.clone(),
+        args: config.interpreters.r.args.clone(),
+    }
+}
+
+#[cfg(feature = "hs")]
+fn hs_options(config: &Config) -> typstpp_hs::HsGlobalOptions {
+    typstpp_hs::HsGlobalOptions {
+        command: config.interpreters.hs.command.clone(),
+        args: config.interpreters.hs.args.clone(),
+    }
+}
+
+#[cfg(feature = "py")]
+fn py_options(config: &Config) -> typstpp_py::PyGlobalOptions {
+    typstpp_py::PyGlobalOptions {
+        figure_path_prefix: Some(config.figure_dir.to_string_lossy().into_owned()),
+        command: config.interpreters.py.command.clone(),
+        args: config.interpreters.py.args.clone(),
+    }
+}
+
+/// Describe an option error along with the `#|` line it came from.
+fn option_error(options: &[RawOption], e: &OptionError) -> String {
+    match options.iter().find(|o| o.name == e.name()) {
+        Some(o) => format!("{}\n{}", e, o.line),
+        None => format!("{}", e),
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct CodeOutput<FO: Display> {
     pub errors: Vec<String>,
     pub outputs: Vec<typstpp_backend::Output<FO>>,
+    /// How long the chunk ran, if it did.
+    pub elapsed: Option<Duration>,
+    /// Whether the result was replayed from the cache.
+    pub cached: bool,
+}
+
+impl<FO: Display> Default for CodeOutput<FO> {
+    fn default() -> Self {
+        CodeOutput {
+            errors: vec![],
+            outputs: vec![],
+            elapsed: None,
+            cached: false,
+        }
+    }
 }
 
 pub struct LanguageDriver<O, FO, B: typstpp_backend::Backend> {
@@ -35,7 +196,19 @@ impl<O, FO, B: typstpp_backend::Backend> LanguageDriver<O, FO, B> {
 
 #[async_trait::async_trait]
 pub trait Preprocess<FO: Display> {
-    async fn preprocess<'a>(&mut self, input: &'a [&CodeChunk]) -> Vec<CodeOutput<FO>>;
+    /// Run chunks of one language, each paired with its position among all
+    /// chunks of that language in the document.
+    ///
+    /// `timeout` applies to chunks without a `timeout` option of their own.
+    async fn preprocess<'a>(
+        &mut self,
+        input: &'a [(usize, &Chunk)],
+        timeout: Option<Duration>,
+    ) -> Vec<CodeOutput<FO>>;
+    /// Whether this language can run alongside other languages.
+    fn concurrent(&self) -> bool;
+    /// Clear the session's state before the document is run again.
+    async fn reset(&mut self) -> Result<(), Error>;
 }
 
 #[async_trait::async_trait]
@@ -44,38 +217,119 @@ where
     O: Send,
     FO: Display + Send,
     B: typstpp_backend::Backend + Send,
-    <B as Backend>::Options: From<HashMap<String, String>>,
+    <B as Backend>::Options: From<ChunkOptions>,
     typstpp_backend::Output<FO>: From<typstpp_backend::Output<<B as Backend>::Output>>,
 {
-    async fn preprocess<'a>(&mut self, input: &'a [&CodeChunk]) -> Vec<CodeOutput<FO>> {
-        let input = input
+    async fn preprocess<'a>(
+        &mut self,
+        input: &'a [(usize, &Chunk)],
+        timeout: Option<Duration>,
+    ) -> Vec<CodeOutput<FO>> {
+        let schema = DRIVER_OPTIONS
             .iter()
-            .map(|c| Input {
-                source: c.code.as_ref(),
-                options: c.options.clone().into(),
-            })
+            .chain(B::option_schema())
+            .copied()
             .collect::<Vec<_>>();
-
-        let result = self.backend.compile(input).await;
-
-        match result {
-            Ok(outputs) => outputs
-                .into_iter()
-                .map(|o| CodeOutput {
+        let mut outputs = Vec::with_capacity(input.len());
+        // Chunks are compiled one at a time so that a backend error only
+        // affects the chunk that caused it.
+        for (index, c) in input {
+            let Some(code) = c.code() else {
+                continue;
+            };
+            let options = match ChunkOptions::parse(
+                code.options
+                    .iter()
+                    .map(|o| (o.name.as_str(), o.value.as_str())),
+                &schema,
+            ) {
+                Ok(options) => options,
+                Err(e) => {
+                    outputs.push(CodeOutput {
+                        errors: vec![option_error(&code.options, &e)],
+                        ..Default::default()
+                    });
+                    continue;
+                }
+            };
+            let output = options.string("output").map(str::to_ascii_lowercase);
+            let (asis, hide) = match output.as_deref() {
+                None | Some("true" | "yes") => (false, false),
+                Some("false" | "no") => (false, true),
+                Some("asis") => (true, false),
+                Some(other) => {
+                    let e = OptionError::Syntax {
+                        name: "output".to_string(),
+                        message: format!("expected `true`, `false` or `asis`, found `{}`", other),
+                    };
+                    outputs.push(CodeOutput {
+                        errors: vec![option_error(&code.options, &e)],
+                        ..Default::default()
+                    });
+                    continue;
+                }
+            };
+            let timeout = options.duration("timeout").or(timeout);
+            let input = Input {
+                source: code.code.as_ref(),
+                options: options.into(),
+                inline: c.is_inline(),
+                index: *index,
+                timeout,
+            };
+            let start = Instant::now();
+            let result = self.backend.compile(vec![input]).await;
+            let elapsed = Some(start.elapsed());
+            outputs.push(match result {
+                Ok(o) => CodeOutput {
                     errors: vec![],
-                    outputs: o.into_iter().map(|o| o.into()).collect(),
-                })
-                .collect(),
-            Err(e) => vec![CodeOutput {
-                errors: vec![format!("{}", e)],
-                outputs: vec![],
-            }],
+                    outputs: o
+                        .into_iter()
+                        .flatten()
+                        .filter(|o| !(hide && o.ty == OutputType::Output))
+                        .map(|o| {
+                            let mut o: typstpp_backend::Output<FO> = o.into();
+                            // What an asis chunk prints is markup, written
+                            // without a fence around it.
+                            if asis && o.ty == OutputType::Output {
+                                o.ty = OutputType::Typst;
+                            }
+                            o
+                        })
+                        .collect(),
+                    elapsed,
+                    cached: false,
+                },
+                Err(e) => CodeOutput {
+                    errors: vec![format!("{}", e)],
+                    outputs: vec![],
+                    elapsed,
+                    cached: false,
+                },
+            });
         }
+        outputs
+    }
+
+    fn concurrent(&self) -> bool {
+        self.backend.concurrent()
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        self.backend
+            .reset()
+            .await
+            .map_err(|e| Error::RuntimeError(format!("{}", e)))
     }
 }
 
 pub struct DocumentDriver<FO> {
-    backends: HashMap<String, Box<dyn Preprocess<FO>>>,
+    backends: HashMap<String, Box<dyn Preprocess<FO> + Send>>,
+    /// The interpreters from `typstpp.toml` the backends were started for.
+    repl: HashMap<String, typstpp_repl::ReplSpec>,
+    interpreters: config::Interpreters,
+    /// Where the backends were told to leave figures.
+    figure_dir: PathBuf,
 }
 
 impl<FO> Default for DocumentDriver<FO>
@@ -94,17 +348,84 @@ where
     pub fn new() -> Self {
         DocumentDriver {
             backends: HashMap::new(),
+            repl: HashMap::new(),
+            interpreters: config::Interpreters::default(),
+            figure_dir: PathBuf::new(),
         }
     }
-    pub fn add_backend(&mut self, name: String, backend: Box<dyn Preprocess<FO>>) {
+    pub fn add_backend(&mut self, name: String, backend: Box<dyn Preprocess<FO> + Send>) {
         self.backends.insert(name, backend);
     }
+
+    /// Whether the driver runs the interpreters `config` asks for, so that
+    /// it can be kept for another run.
+    pub fn serves(&self, config: &Config) -> bool {
+        self.repl == config.repl
+            && self.interpreters == config.interpreters
+            && self.figure_dir == config.figure_dir
+    }
+
+    /// Clear the state of every session, so that a document run again
+    /// starts clean while interpreters that can be reset keep running.
+    pub async fn reset(&mut self) -> Result<(), Error> {
+        for backend in self.backends.values_mut() {
+            backend.reset().await?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem with one chunk, reported in the output document and on the
+/// terminal.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+    /// Whether the chunk is marked `#| error: true`.
+    pub allowed: bool,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+/// What went wrong while preprocessing a document that still produced
+/// output.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+    /// The files the document was made from: the document itself, the
+    /// files it includes or imports and the files its chunks read, but not
+    /// the figures and cache typstpp writes.
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl Report {
+    /// Errors from chunks that were not expected to fail.
+    pub fn failures(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|d| !d.allowed)
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     IO(tokio::io::Error),
     RuntimeError(String),
+    Config(String),
+    Parse(Diagnostic),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::RuntimeError(s) => write!(f, "{}", s),
+            Error::Config(s) => write!(f, "invalid config: {}", s),
+            Error::Parse(d) => write!(f, "{}", d),
+        }
+    }
 }
 
 impl From<tokio::io::Error> for Error {
@@ -113,125 +434,370 @@ impl From<tokio::io::Error> for Error {
     }
 }
 
-pub async fn preprocess_typst<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+/// Preprocess the document read from `reader`, found at `path`, writing
+/// the result to `output`.
+pub async fn preprocess_typst<R: AsyncRead + Unpin, O: OutputFile>(
+    path: &Path,
     reader: R,
-    mut writer: W,
-) -> Result<(), Error> {
-    let mut driver: DocumentDriver<String> = DocumentDriver::new();
-    #[cfg(feature = "r")]
-    driver.add_backend(
-        "r".to_string(),
-        Box::new(LanguageDriver::<typstpp_r::ROptions, _, _>::new(
-            typstpp_r::RBackend::new(typstpp_r::RGlobalOptions::default())
-                .await
-                .unwrap(),
-        )),
-    );
-    #[cfg(feature = "hs")]
-    driver.add_backend(
-        "hs".to_string(),
-        Box::new(LanguageDriver::<typstpp_hs::HsOptions, _, _>::new(
-            typstpp_hs::HsBackend::new(()).await.unwrap(),
-        )),
-    );
-    writer.write_all(include_bytes!("prelude.typ")).await?;
-
-    let mut input = io::InputTypstFile::new(reader);
-    let mut output = io::OutputTypstFile::new(writer);
-
-    let mut chunks = Vec::new();
-    while let Some(chunk) = input.read_chunk().await? {
-        chunks.push(chunk);
-    }
-    let code_chunks = chunks.iter_mut().filter_map(|c| match c {
-        source::Chunk::Code(c) => Some(c),
-        _ => None,
-    });
+    output: &mut O,
+    config: &Config,
+) -> Result<Report, Error> {
+    DocumentDriver::from_config(config)
+        .await?
+        .preprocess(path, reader, output, config)
+        .await
+}
 
-    let mut code_chunks_by_lang = HashMap::new();
-    for c in code_chunks {
-        if let Some(file) = c.options.get("file") {
-            c.code = fs::read_to_string(file).await?;
-        }
-        code_chunks_by_lang
-            .entry(c.lang.clone())
-            .or_insert_with(Vec::new)
-            .push(&*c);
-    }
-    let mut code_outputs_by_lang = HashMap::new();
-    for (lang, chunks) in code_chunks_by_lang {
-        if let Some(backend) = driver.backends.get_mut(&lang) {
-            let result = backend.preprocess(&chunks).await;
-            code_outputs_by_lang.insert(lang.clone(), VecDeque::from(result));
-        } else {
-            code_outputs_by_lang.insert(
-                lang.clone(),
-                chunks
-                    .iter()
-                    .map(|c| CodeOutput {
-                        errors: vec![],
-                        outputs: vec![typstpp_backend::Output {
-                            data: c.code.clone(),
-                            ty: typstpp_backend::OutputType::Code,
-                        }],
-                    })
-                    .collect::<VecDeque<_>>(),
+impl DocumentDriver<String> {
+    /// A driver with the built-in languages and the interpreters set up in
+    /// `config`.
+    pub async fn from_config(config: &Config) -> Result<Self, Error> {
+        let mut driver = DocumentDriver::new();
+        #[cfg(feature = "r")]
+        driver.add_backend(
+            "r".to_string(),
+            Box::new(LanguageDriver::<typstpp_r::ROptions, _, _>::new(
+                typstpp_r::RBackend::new(r_options(config))
+                    .await
+                    .map_err(|e| Error::RuntimeError(format!("{}", e)))?,
+            )),
+        );
+        #[cfg(feature = "hs")]
+        driver.add_backend(
+            "hs".to_string(),
+            Box::new(LanguageDriver::<typstpp_hs::HsOptions, _, _>::new(
+                typstpp_hs::HsBackend::new(hs_options(config))
+                    .await
+                    .map_err(|e| Error::RuntimeError(format!("{}", e)))?,
+            )),
+        );
+        #[cfg(feature = "py")]
+        driver.add_backend(
+            "py".to_string(),
+            Box::new(LanguageDriver::<typstpp_py::PyOptions, _, _>::new(
+                typstpp_py::PyBackend::new(py_options(config))
+                    .await
+                    .map_err(|e| Error::RuntimeError(format!("{}", e)))?,
+            )),
+        );
+        for (name, spec) in &config.repl {
+            driver.add_backend(
+                name.clone(),
+                Box::new(LanguageDriver::<typstpp_repl::ReplOptions, _, _>::new(
+                    typstpp_repl::ReplBackend::new(spec.clone())
+                        .await
+                        .map_err(|e| Error::RuntimeError(format!("{}", e)))?,
+                )),
             );
         }
+        driver.repl = config.repl.clone();
+        driver.interpreters = config.interpreters.clone();
+        driver.figure_dir = config.figure_dir.clone();
+        Ok(driver)
     }
-    for chunk in chunks {
-        match chunk {
-            source::Chunk::Verbatim(s) => output.write_chunk(&source::Chunk::Verbatim(s)).await?,
-            source::Chunk::Code(c) => {
-                let lang = c.lang.clone();
-                let outputs = code_outputs_by_lang
-                    .get_mut(&lang)
-                    .and_then(|o| o.pop_front())
-                    .unwrap_or_else(|| CodeOutput {
-                        errors: vec![],
-                        outputs: vec![],
-                    });
-                for e in outputs.errors {
-                    output.write_chunk(&source::Chunk::Error(e)).await?;
-                }
-                for o in outputs.outputs {
-                    match o.ty {
-                        typstpp_backend::OutputType::Typst => {
-                            output
-                                .write_chunk(&source::Chunk::Verbatim(o.data.to_string()))
-                                .await?
-                        }
-                        typstpp_backend::OutputType::Code => {
-                            output
-                                .write_chunk(&source::Chunk::Code(CodeChunk {
-                                    lang: c.lang.clone(),
-                                    options: Default::default(),
-                                    code: o.data,
-                                }))
-                                .await?;
-                        }
-                        typstpp_backend::OutputType::Output => {
-                            output.write_chunk(&source::Chunk::Output(o)).await?
-                        }
-                        typstpp_backend::OutputType::Message => {
-                            output
-                                .write_chunk(&source::Chunk::Message(o.data.to_string()))
-                                .await?
-                        }
-                        typstpp_backend::OutputType::Error => {
-                            output
-                                .write_chunk(&source::Chunk::Error(o.data.to_string()))
-                                .await?
+
+    /// Preprocess the document read from `reader`, found at `path`, in the
+    /// sessions of this driver, writing the result to `output`.
+    pub async fn preprocess<R: AsyncRead + Unpin, O: OutputFile>(
+        &mut self,
+        path: &Path,
+        reader: R,
+        output: &mut O,
+        config: &Config,
+    ) -> Result<Report, Error> {
+        let mut input = io::InputTypstFile::new(reader, path);
+
+        let mut document = Vec::new();
+        while let Some(chunk) = input.read_chunk().await? {
+            document.push(chunk);
+        }
+        let mut dependencies = vec![path.to_path_buf()];
+        dependencies.extend(input.includes().iter().cloned());
+
+        // Included files are expanded in place, depth first, so that their
+        // chunks run in document order in the same sessions. Each file is
+        // expanded once; including it again refers to the same copy.
+        let root = path.parent().unwrap_or(Path::new(""));
+        let absolute = |p: &Path| std::path::absolute(p).unwrap_or_else(|_| p.to_path_buf());
+        let mut visited = HashSet::from([absolute(path)]);
+        let mut chunks = Vec::new();
+        let mut stack = vec![document.into_iter()];
+        while let Some(file_chunks) = stack.last_mut() {
+            let Some(mut chunk) = file_chunks.next() else {
+                stack.pop();
+                continue;
+            };
+            if let Chunk::Include(i) = &chunk.chunk {
+                let file = io::resolve_include(root, &chunk.span.file, &i.path);
+                if !visited.contains(&absolute(&file)) {
+                    match fs::read_to_string(&file).await {
+                        Ok(text) => {
+                            visited.insert(absolute(&file));
+                            let (included, includes) = io::parse_document(&text, &file)?;
+                            dependencies.extend(
+                                includes.iter().map(|p| io::resolve_include(root, &file, p)),
+                            );
+                            chunks.push(chunk);
+                            stack.push(included.into_iter());
+                            continue;
                         }
+                        // Left as written for Typst to report.
+                        Err(_) => chunk.chunk = Chunk::Verbatim(i.literal.clone()),
                     }
                 }
             }
-            source::Chunk::Output(o) => output.write_chunk(&source::Chunk::Output(o)).await?,
-            source::Chunk::Message(m) => output.write_chunk(&source::Chunk::Message(m)).await?,
-            source::Chunk::Error(e) => output.write_chunk(&source::Chunk::Error(e)).await?,
-            source::Chunk::Graphics(g) => output.write_chunk(&source::Chunk::Graphics(g)).await?,
+            chunks.push(chunk);
+        }
+
+        for chunk in chunks.iter_mut() {
+            if let source::Chunk::Code(c) = &mut chunk.chunk {
+                // Options from `typstpp.toml` come first, so that those of
+                // the chunk are reported against its own lines.
+                if let Some(defaults) = config.defaults.get(&c.lang) {
+                    let mut options = defaults
+                        .iter()
+                        .filter(|(name, _)| !c.options.iter().any(|o| &o.name == *name))
+                        .map(|(name, value)| RawOption {
+                            name: name.clone(),
+                            value: value.clone(),
+                            line: format!(
+                                "{}: [defaults.{}] {} = {}",
+                                config::Config::FILE_NAME,
+                                c.lang,
+                                name,
+                                value
+                            ),
+                        })
+                        .collect::<Vec<_>>();
+                    options.append(&mut c.options);
+                    c.options = options;
+                }
+                // Like an include, the path is relative to the file the
+                // chunk is in, or to the project root with a leading `/`.
+                if let Some(file) = c.option("file") {
+                    let file = io::resolve_include(
+                        root,
+                        &chunk.span.file,
+                        file.as_str().unwrap_or_default(),
+                    );
+                    c.code = fs::read_to_string(&file).await?;
+                    dependencies.push(file);
+                }
+            }
         }
+
+        dependencies.extend(
+            chunks
+                .iter()
+                .filter_map(|c| c.chunk.code())
+                .flat_map(|c| c.dependencies()),
+        );
+        // Chunks reading what typstpp writes must not make `watch` loop.
+        let generated = [Some(&config.figure_dir), config.cache_dir.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|d| std::path::absolute(d).ok())
+            .collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        dependencies.retain(|d| {
+            let Ok(absolute) = std::path::absolute(d) else {
+                return false;
+            };
+            !generated.iter().any(|g| absolute.starts_with(g)) && seen.insert(absolute)
+        });
+
+        let mut code_chunks_by_lang = HashMap::new();
+        for c in chunks.iter() {
+            if let Some(code) = c.chunk.code() {
+                code_chunks_by_lang
+                    .entry(code.lang.clone())
+                    .or_insert_with(Vec::new)
+                    .push(&c.chunk);
+            }
+        }
+        let mut code_outputs_by_lang = HashMap::new();
+        let mut exclusive = Vec::new();
+        let mut tasks = Vec::new();
+        for (lang, chunks) in code_chunks_by_lang {
+            if let Some(backend) = self.backends.remove(&lang) {
+                // Each language gets its own task, so that languages with
+                // separate sessions run at the same time.
+                let concurrent = backend.concurrent();
+                let timeout = config.timeout;
+                let chunks = chunks.into_iter().cloned().collect::<Vec<_>>();
+                let cache = config
+                    .cache_dir
+                    .clone()
+                    .filter(|_| config.cache)
+                    .map(Cache::new);
+                let run = async move {
+                    let mut backend = backend;
+                    let chunks = chunks.iter().collect::<Vec<_>>();
+                    let result =
+                        execute_cached(backend.as_mut(), &lang, &chunks, cache.as_ref(), timeout)
+                            .await;
+                    (lang, backend, result)
+                };
+                if concurrent {
+                    tasks.push(tokio::spawn(run));
+                } else {
+                    exclusive.push(run);
+                }
+            } else {
+                code_outputs_by_lang.insert(
+                    lang.clone(),
+                    chunks
+                        .iter()
+                        .filter_map(|c| match c {
+                            source::Chunk::Inline(c) => Some(CodeOutput {
+                                outputs: vec![typstpp_backend::Output {
+                                    data: format!("`{} {}`", c.lang, c.code),
+                                    ty: typstpp_backend::OutputType::Typst,
+                                }],
+                                ..Default::default()
+                            }),
+                            source::Chunk::Code(c) => Some(CodeOutput {
+                                outputs: vec![typstpp_backend::Output {
+                                    data: c.code.clone(),
+                                    ty: typstpp_backend::OutputType::Code,
+                                }],
+                                ..Default::default()
+                            }),
+                            _ => None,
+                        })
+                        .collect::<VecDeque<_>>(),
+                );
+            }
+        }
+        // Every backend is put back before any error is returned, so that a
+        // failing language doesn't take the others' sessions with it.
+        let mut finished = Vec::new();
+        let mut failure = None;
+        for task in tasks {
+            match task.await {
+                Ok(run) => finished.push(run),
+                Err(e) => {
+                    failure.get_or_insert(Error::RuntimeError(format!("{}", e)));
+                }
+            }
+        }
+        for run in exclusive {
+            finished.push(run.await);
+        }
+        for (lang, backend, result) in finished {
+            self.backends.insert(lang.clone(), backend);
+            match result {
+                Ok(result) => {
+                    code_outputs_by_lang.insert(lang, result);
+                }
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let mut report = Report {
+            dependencies,
+            ..Default::default()
+        };
+        output.start().await?;
+        for source in chunks {
+            let mut result = match source.chunk.code() {
+                Some(c) => code_outputs_by_lang
+                    .get_mut(&c.lang)
+                    .and_then(|o| o.pop_front())
+                    .unwrap_or_default(),
+                None => CodeOutput::default(),
+            };
+            // Typst cannot decode a PDF from bytes, so an embedded one is
+            // reported in place of the image.
+            if config.graphics == GraphicsMode::Embed {
+                for o in &mut result.outputs {
+                    if matches!(&o.ty, OutputType::Graphics(g) if g.ty == GraphicsType::Pdf) {
+                        *o = typstpp_backend::Output {
+                            data: "PDF images cannot be embedded; use `graphics = \"files\"` \
+                                   or another image format"
+                                .to_string(),
+                            ty: OutputType::Error,
+                        };
+                    }
+                }
+            }
+            let chunk = ExecutedChunk { source, result };
+            report.diagnostics.extend(chunk.diagnostics());
+            output.write_chunk(&chunk).await?;
+        }
+        output.finish().await?;
+
+        Ok(report)
+    }
+}
+
+/// Run the chunks of one language, replaying cached results where possible.
+async fn execute_cached(
+    backend: &mut (dyn Preprocess<String> + Send + '_),
+    lang: &str,
+    chunks: &[&Chunk],
+    cache: Option<&Cache>,
+    timeout: Option<Duration>,
+) -> Result<VecDeque<CodeOutput<String>>, Error> {
+    let mut keys = Vec::with_capacity(chunks.len());
+    let mut results = Vec::with_capacity(chunks.len());
+    let mut misses = Vec::new();
+    for (i, c) in chunks.iter().enumerate() {
+        let Some(code) = c.code() else {
+            continue;
+        };
+        // A file that cannot be read counts as empty, so that the chunk
+        // reruns once it appears.
+        let mut dependencies = Vec::new();
+        for path in code.dependencies() {
+            dependencies.push(fs::read(path).await.unwrap_or_default());
+        }
+        let key = Cache::key(
+            lang,
+            &code.code,
+            c.is_inline(),
+            &code.options,
+            &dependencies,
+            keys.last().map(String::as_str),
+        );
+        let cacheable = code.option("cache").and_then(|v| v.as_bool()) != Some(false);
+        let hit = match cache {
+            Some(cache) if cacheable => cache.get(&key).await,
+            _ => None,
+        };
+        if hit.is_none() {
+            misses.push((i, *c));
+        }
+        results.push(hit);
+        keys.push(key);
+    }
+    // Each key covers the chunks before it, so the chunks before the first
+    // miss are replayed, and every chunk from there on runs again in order.
+    if let Some(&(first, _)) = misses.first() {
+        misses = chunks
+            .iter()
+            .enumerate()
+            .skip(first)
+            .filter(|(_, c)| c.code().is_some())
+            .map(|(i, c)| (i, *c))
+            .collect();
     }
 
-    Ok(())
+    let outputs = backend.preprocess(&misses, timeout).await;
+    for ((i, c), output) in misses.iter().zip(outputs) {
+        let cacheable = c
+            .code()
+            .and_then(|c| c.option("cache"))
+            .and_then(|v| v.as_bool())
+            != Some(false);
+        if let Some(cache) = cache.filter(|_| cacheable && output.errors.is_empty()) {
+            cache.put(&keys[*i], &output).await?;
+        }
+        results[*i] = Some(output);
+    }
+    Ok(results.into_iter().flatten().collect())
 }
@@ -1,13 +1,24 @@
-use std::{path::Path, process::ExitStatus, time::Duration};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use crossterm::style::Stylize;
 use notify_debouncer_full::{
     new_debouncer,
     notify::{RecursiveMode, Watcher},
 };
 use tokio::{fs::File, process::Command, select};
-use typstpp::{preprocess_typst, Error};
+use typstpp::{
+    common_options,
+    config::{Config, FailurePolicy},
+    probe_languages, DocumentDriver, Error, LanguageInfo, OutputJsonFile, OutputTypstFile, Report,
+    SourceMap,
+};
+use typstpp_backend::parse_duration;
 
 #[derive(Debug, Parser)]
 #[clap(name = "typstpp", version, author, about)]
@@ -18,7 +29,7 @@ struct CliArgs {
 
 #[derive(Debug, Subcommand)]
 enum SubCommand {
-    #[clap(about = "Print typstpp info")]
+    #[clap(about = "Print the available languages and their interpreters")]
     Info,
     #[clap(about = "Preprocess a typst file")]
     Preprocess(PreprocessArgs),
@@ -30,18 +41,53 @@ enum SubCommand {
 
 #[derive(Debug, Parser)]
 struct PreprocessArgs {
-    #[clap(short, long)]
-    input: String,
+    #[clap(
+        short,
+        long,
+        help = "The document, instead of the entries of typstpp.toml"
+    )]
+    input: Option<String>,
     #[clap(short, long)]
     output: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        default_value = "typst",
+        help = "Write the preprocessed document, or the executed chunks as JSON"
+    )]
+    emit: Emit,
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+/// What `preprocess` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    Typst,
+    Json,
+}
+
+impl Emit {
+    fn extension(&self) -> &'static str {
+        match self {
+            Emit::Typst => ".out.typ",
+            Emit::Json => ".out.json",
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
 struct CompileArgs {
-    #[clap(short, long)]
-    input: String,
+    #[clap(
+        short,
+        long,
+        help = "The document, instead of the entries of typstpp.toml"
+    )]
+    input: Option<String>,
     #[clap(short, long)]
     output: Option<String>,
+    #[clap(flatten)]
+    run: RunArgs,
 
     #[clap(last = true)]
     typst_args: Vec<String>,
@@ -49,15 +95,60 @@ struct CompileArgs {
 
 #[derive(Debug, Parser)]
 struct WatchArgs {
-    #[clap(short, long)]
-    input: String,
+    #[clap(
+        short,
+        long,
+        help = "The document, instead of the entries of typstpp.toml"
+    )]
+    input: Option<String>,
     #[clap(short, long)]
     output: Option<String>,
+    #[clap(flatten)]
+    run: RunArgs,
 
     #[clap(last = true)]
     typst_args: Vec<String>,
 }
 
+/// Settings that override `typstpp.toml`.
+#[derive(Debug, Parser)]
+struct RunArgs {
+    #[clap(long, help = "Run every chunk instead of replaying cached results")]
+    no_cache: bool,
+    #[clap(
+        long,
+        conflicts_with = "keep_going",
+        help = "Fail if any chunk fails, unless it is marked `#| error: true`"
+    )]
+    strict: bool,
+    #[clap(long, help = "Render errors in place of failing chunks and carry on")]
+    keep_going: bool,
+    #[clap(
+        long,
+        value_parser = parse_timeout,
+        help = "Stop chunks that run longer than this, e.g. 30s"
+    )]
+    timeout: Option<Duration>,
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    parse_duration(s).ok_or_else(|| format!("invalid duration `{}`", s))
+}
+
+impl RunArgs {
+    fn apply(&self, config: &mut Config) {
+        config.cache &= !self.no_cache;
+        if self.strict {
+            config.failure = FailurePolicy::Strict;
+        } else if self.keep_going {
+            config.failure = FailurePolicy::KeepGoing;
+        }
+        if self.timeout.is_some() {
+            config.timeout = self.timeout;
+        }
+    }
+}
+
 const VERB_WIDTH: usize = 15;
 
 fn pad(s: &str) -> String {
@@ -102,15 +193,33 @@ impl From<ExitStatus> for CompileError {
 
 async fn compile_typst(
     input: &str,
-    output: Option<&str>,
-    typst_args: &Vec<String>,
+    output: Option<&Path>,
+    typst_args: &[String],
 ) -> Result<(), CompileError> {
     let mut cmd = Command::new("typst");
     cmd.arg("compile").args(typst_args).arg(input);
     if let Some(output) = output {
         cmd.arg(output);
     }
-    let exit = cmd.spawn()?.wait().await?;
+    let result = cmd
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+    // Typst's diagnostics point into the preprocessed document and the
+    // copies of the files it includes, relative to the project root; point
+    // them back into the originals where they have a map.
+    let root = Path::new(input).parent().unwrap_or(Path::new(""));
+    let original = String::from_utf8_lossy(&result.stderr);
+    let mut stderr = original.to_string();
+    for file in SourceMap::files(&original) {
+        let path = root.join(file.trim_start_matches('/'));
+        if let Some(map) = SourceMap::read(&SourceMap::path_for(&path)).await {
+            stderr = map.rewrite(Path::new(file), &stderr);
+        }
+    }
+    eprint!("{}", stderr);
+    let exit = result.status;
     if exit.success() {
         Ok(())
     } else {
@@ -120,8 +229,8 @@ async fn compile_typst(
 
 async fn compile_typst_and_log(
     input: &str,
-    output: Option<&str>,
-    typst_args: &Vec<String>,
+    output: Option<&Path>,
+    typst_args: &[String],
 ) -> Result<(), CompileError> {
     if typst_args.is_empty() {
         log_process("Compiling", input);
@@ -144,33 +253,300 @@ async fn compile_typst_and_log(
     }
 }
 
-async fn preprocess(inputf: &str, output: &str) -> Result<(), Error> {
+/// Compile `output`, preprocessed from `input`, as `config` says, unless
+/// Typst arguments were given on the command line.
+async fn compile_document(
+    input: &str,
+    output: &str,
+    config: &Config,
+    typst_args: &[String],
+) -> Result<(), CompileError> {
+    let typst_args = if typst_args.is_empty() {
+        &config.typst_args
+    } else {
+        typst_args
+    };
+    let document = match &config.output_dir {
+        Some(dir) => {
+            tokio::fs::create_dir_all(dir).await?;
+            let name = Path::new(input).with_extension("pdf");
+            Some(dir.join(name.file_name().unwrap_or_default()))
+        }
+        None => None,
+    };
+    compile_typst_and_log(output, document.as_deref(), typst_args).await
+}
+
+/// What is kept from one run to the next while watching.
+#[derive(Default)]
+struct Session {
+    /// The backends, kept running so that interpreters start only once.
+    driver: Option<DocumentDriver<String>>,
+    /// The files the last run depended on.
+    dependencies: Vec<PathBuf>,
+}
+
+async fn preprocess(
+    inputf: &str,
+    outputf: &str,
+    run: &RunArgs,
+    emit: Emit,
+    session: &mut Session,
+) -> Result<(Report, Config), Error> {
+    let mut config = Config::load_for(inputf).await?;
+    run.apply(&mut config);
+    // Sessions from the last run are reset rather than restarted, unless
+    // the interpreters they run have changed in the meantime.
+    let reused = match session.driver.as_mut() {
+        Some(driver) if driver.serves(&config) => driver.reset().await.is_ok(),
+        _ => false,
+    };
+    if !reused {
+        session.driver = Some(DocumentDriver::from_config(&config).await?);
+    }
+    let driver = session.driver.as_mut().unwrap();
+    let path = Path::new(inputf);
     let mut input = File::open(inputf).await?;
-    let mut output = File::create(output).await?;
-    preprocess_typst(&mut input, &mut output).await?;
-    Ok(())
+    let output = File::create(outputf).await?;
+    let report = match emit {
+        Emit::Typst => {
+            let prelude = match &config.prelude {
+                Some(path) => tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?,
+                None => String::new(),
+            };
+            let mut output = OutputTypstFile::new(output)
+                .with_graphics(config.graphics, config.figure_dir.clone())
+                .with_output_path(outputf)
+                .with_prelude(&prelude);
+            let report = driver
+                .preprocess(path, &mut input, &mut output, &config)
+                .await?;
+            output
+                .source_map(path)
+                .write(&SourceMap::path_for(Path::new(outputf)))
+                .await?;
+            report
+        }
+        Emit::Json => {
+            let mut output = OutputJsonFile::new(output, config.figure_dir.clone());
+            driver
+                .preprocess(path, &mut input, &mut output, &config)
+                .await?
+        }
+    };
+    Ok((report, config))
 }
 
-async fn preprocess_and_log(inputf: &str, output: &str) -> Result<(), Error> {
+/// Preprocess `inputf` in `session`, recording the files the run depended
+/// on even if a chunk failed, and return the configuration it ran with.
+async fn preprocess_and_log(
+    inputf: &str,
+    output: &str,
+    run: &RunArgs,
+    emit: Emit,
+    session: &mut Session,
+) -> Result<Config, Error> {
     log_process("Preprocessing", inputf);
     let start = std::time::Instant::now();
-    match preprocess(inputf, output).await {
-        Ok(_) => {
+    match preprocess(inputf, output, run, emit, session).await {
+        Ok((report, config)) => {
+            session.dependencies.clone_from(&report.dependencies);
+            for diagnostic in report.failures() {
+                log_err("Error", &diagnostic.to_string());
+            }
+            let mut failed = report.failures().map(|d| &d.span).collect::<Vec<_>>();
+            failed.dedup();
+            if config.failure == FailurePolicy::Strict && !failed.is_empty() {
+                log_err(
+                    "Failed",
+                    &format!("{}: {} chunk(s) failed", inputf, failed.len()),
+                );
+                for span in &failed {
+                    eprintln!("{} {}", pad(""), span);
+                }
+                return Err(Error::RuntimeError(format!(
+                    "{} chunk(s) failed",
+                    failed.len()
+                )));
+            }
             log_success("Preprocessed", start.elapsed(), inputf);
-            Ok(())
+            Ok(config)
         }
         Err(e) => {
+            log_err("Error", &e.to_string());
             log_err("Failed", inputf);
             Err(e)
         }
     }
 }
 
-fn infer_preprocess_output<P: AsRef<Path>>(input: P) -> String {
+fn print_info(languages: &[LanguageInfo]) {
+    let names = |options: &[typstpp_backend::OptionSpec]| {
+        options
+            .iter()
+            .map(|o| o.name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let rows = languages
+        .iter()
+        .map(|l| {
+            [
+                l.lang.clone(),
+                match &l.info.missing {
+                    None => "available".to_string(),
+                    Some(reason) => format!("missing: {}", reason),
+                },
+                l.info
+                    .path
+                    .as_ref()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                l.info
+                    .versions
+                    .iter()
+                    .map(|(name, v)| format!("{} {}", name, v))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                names(l.options),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["Language", "Status", "Path", "Versions", "Options"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: &[&str]| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(c, w)| format!("{:w$}", c, w = w))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(&header).bold());
+    for row in &rows {
+        let cells = row.each_ref().map(String::as_str);
+        if row[1] == "available" {
+            println!("{}", line(&cells));
+        } else {
+            println!("{}", line(&cells).red());
+        }
+    }
+    println!();
+    println!("Options for every language: {}", names(common_options()));
+}
+
+/// The absolute paths of the files `watch` reruns on, leaving out the
+/// output it writes itself.
+fn watched_files(dependencies: &[PathBuf], output: &Path) -> HashSet<PathBuf> {
+    let generated = [output.to_path_buf(), SourceMap::path_for(output)]
+        .iter()
+        .filter_map(|p| std::path::absolute(p).ok())
+        .collect::<Vec<_>>();
+    dependencies
+        .iter()
+        .filter_map(|d| std::path::absolute(d).ok())
+        .filter(|d| !generated.contains(d))
+        .collect()
+}
+
+fn infer_preprocess_output<P: AsRef<Path>>(input: P, emit: Emit) -> String {
     let input = input.as_ref();
     let mut output = input.file_stem().unwrap().to_os_string();
-    output.push(".out.typ");
-    output.into_string().unwrap()
+    output.push(emit.extension());
+    // Next to the input, so that the paths in it still resolve.
+    input
+        .with_file_name(output)
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+/// The documents to process with where their output goes: the input given
+/// on the command line, or else the entries of the project in the working
+/// directory.
+async fn targets(
+    input: Option<String>,
+    output: Option<String>,
+    emit: Emit,
+) -> Result<Vec<(String, String)>, Error> {
+    let inputs = match input {
+        Some(input) => vec![input],
+        None => Config::discover(Path::new(""))
+            .await?
+            .entries
+            .iter()
+            .map(|e| e.to_string_lossy().into_owned())
+            .collect(),
+    };
+    if inputs.is_empty() {
+        return Err(Error::RuntimeError(format!(
+            "no input given and no entries in {}",
+            Config::FILE_NAME
+        )));
+    }
+    if output.is_some() && inputs.len() > 1 {
+        return Err(Error::RuntimeError(
+            "--output needs a single input".to_string(),
+        ));
+    }
+    Ok(inputs
+        .into_iter()
+        .map(|input| {
+            let output = output
+                .clone()
+                .unwrap_or_else(|| infer_preprocess_output(&input, emit));
+            (input, output)
+        })
+        .collect())
+}
+
+/// A document being watched.
+struct Target {
+    input: String,
+    output: String,
+    session: Session,
+}
+
+impl Target {
+    async fn build(&mut self, run: &RunArgs, typst_args: &[String]) {
+        if let Ok(config) = preprocess_and_log(
+            &self.input,
+            &self.output,
+            run,
+            Emit::Typst,
+            &mut self.session,
+        )
+        .await
+        {
+            compile_document(&self.input, &self.output, &config, typst_args)
+                .await
+                .ok();
+        }
+    }
+}
+
+/// The targets, or the reason there are none, ending the process.
+async fn targets_or_exit(
+    input: Option<String>,
+    output: Option<String>,
+    emit: Emit,
+) -> Vec<(String, String)> {
+    match targets(input, output, emit).await {
+        Ok(targets) => targets,
+        Err(e) => {
+            log_err("Error", &e.to_string());
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
@@ -178,64 +554,132 @@ async fn main() {
     let cli = CliArgs::parse();
     match cli.subcmd {
         SubCommand::Info => {
-            #[allow(unused_mut)]
-            let mut supported_langs: Vec<&'static str> = Vec::new();
-            #[cfg(feature = "r")]
-            supported_langs.push("r");
-            #[cfg(feature = "hs")]
-            supported_langs.push("hs");
-            println!("Supported languages: {}", supported_langs.join(" "));
+            let config = match Config::discover(Path::new("")).await {
+                Ok(config) => config,
+                Err(e) => {
+                    log_err("Error", &e.to_string());
+                    Config::default()
+                }
+            };
+            print_info(&probe_languages(&config).await);
         }
         SubCommand::Preprocess(args) => {
-            let output = args
-                .output
-                .unwrap_or_else(|| infer_preprocess_output(&args.input).as_str().to_string());
-            preprocess_and_log(&args.input, &output).await.unwrap();
+            let mut failed = false;
+            for (input, output) in targets_or_exit(args.input, args.output, args.emit).await {
+                failed |= preprocess_and_log(
+                    &input,
+                    &output,
+                    &args.run,
+                    args.emit,
+                    &mut Session::default(),
+                )
+                .await
+                .is_err();
+            }
+            if failed {
+                std::process::exit(1);
+            }
         }
         SubCommand::Compile(args) => {
-            let output = args
-                .output
-                .unwrap_or_else(|| infer_preprocess_output(&args.input).as_str().to_string());
-            preprocess_and_log(&args.input, &output).await.unwrap();
-            compile_typst_and_log(&output, None, &args.typst_args)
+            let mut failed = false;
+            for (input, output) in targets_or_exit(args.input, args.output, Emit::Typst).await {
+                let Ok(config) = preprocess_and_log(
+                    &input,
+                    &output,
+                    &args.run,
+                    Emit::Typst,
+                    &mut Session::default(),
+                )
                 .await
-                .unwrap();
+                else {
+                    failed = true;
+                    continue;
+                };
+                failed |= compile_document(&input, &output, &config, &args.typst_args)
+                    .await
+                    .is_err();
+            }
+            if failed {
+                std::process::exit(1);
+            }
         }
         SubCommand::Watch(args) => {
-            let output = args
-                .output
-                .unwrap_or_else(|| infer_preprocess_output(&args.input).as_str().to_string());
-            preprocess_and_log(&args.input, &output).await.ok();
-            compile_typst_and_log(&output, None, &args.typst_args)
+            let mut targets = targets_or_exit(args.input, args.output, Emit::Typst)
                 .await
-                .ok();
+                .into_iter()
+                .map(|(input, output)| Target {
+                    session: Session {
+                        dependencies: vec![PathBuf::from(&input)],
+                        ..Default::default()
+                    },
+                    input,
+                    output,
+                })
+                .collect::<Vec<_>>();
+            for target in targets.iter_mut() {
+                target.build(&args.run, &args.typst_args).await;
+            }
 
             let (tx, mut rx) = tokio::sync::mpsc::channel(1);
             let mut debouncer = new_debouncer(Duration::from_secs(1), None, move |res| match res {
-                Ok(event) => {
-                    tx.blocking_send(event).unwrap();
+                Ok(events) => {
+                    tx.blocking_send(events).unwrap();
                 }
                 Err(e) => {
                     eprintln!("watch error: {:?}", e);
                 }
             })
             .unwrap();
-            debouncer
-                .watcher()
-                .watch(Path::new(&args.input), RecursiveMode::NonRecursive)
-                .unwrap();
 
+            // Directories are watched rather than files, so that editors
+            // replacing a file on save are noticed; events for other files
+            // in them, such as the output and figures, are ignored.
+            let mut watched = HashSet::new();
             loop {
-                log_process("Watching", &args.input);
-                select! {
+                let files = targets
+                    .iter()
+                    .map(|t| watched_files(&t.session.dependencies, Path::new(&t.output)))
+                    .collect::<Vec<_>>();
+                let dirs = files
+                    .iter()
+                    .flatten()
+                    .filter_map(|f| f.parent().map(Path::to_path_buf))
+                    .collect::<HashSet<_>>();
+                for dir in watched.difference(&dirs) {
+                    debouncer.watcher().unwatch(dir).ok();
+                }
+                for dir in dirs.difference(&watched) {
+                    if let Err(e) = debouncer.watcher().watch(dir, RecursiveMode::NonRecursive) {
+                        log_err("Error", &format!("cannot watch {}: {}", dir.display(), e));
+                    }
+                }
+                watched = dirs;
+
+                let inputs = targets.iter().map(|t| t.input.as_str()).collect::<Vec<_>>();
+                log_process("Watching", &inputs.join(", "));
+                let changed = loop {
+                    select! {
                         _ = tokio::signal::ctrl_c() => {
                             debouncer.stop();
                             return;
                         }
-                        Some(_) = rx.recv() => {
-                            preprocess_and_log(&args.input, &output).await.ok();
-                            compile_typst_and_log(&output, None, &args.typst_args).await.ok();
+                        Some(events) = rx.recv() => {
+                            let changed = files
+                                .iter()
+                                .map(|files| {
+                                    events.iter().any(|e| e.paths.iter().any(|p| files.contains(p)))
+                                })
+                                .collect::<Vec<_>>();
+                            if changed.contains(&true) {
+                                break changed;
+                            }
                         }
+                    }
+                };
+                for (target, changed) in targets.iter_mut().zip(changed) {
+                    if changed {
+                        target.build(&args.run, &args.typst_args).await;
+                    }
                 }
             }
         }
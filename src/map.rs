@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// Where each line of a preprocessed document came from, kept next to it
+/// so that Typst's diagnostics can point into the document the user edits.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    /// The original document.
+    pub source: PathBuf,
+    /// The 1-based line of the original document that each line of the
+    /// output came from; output of a chunk comes from the chunk's first
+    /// line, and lines with no source, such as the prelude, are 0.
+    pub lines: Vec<usize>,
+}
+
+impl SourceMap {
+    /// The sidecar file holding the map of the preprocessed document at
+    /// `output`.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_os_string();
+        path.push(".map");
+        PathBuf::from(path)
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<(), tokio::io::Error> {
+        fs::write(path, serde_json::to_vec(self)?).await
+    }
+
+    /// Read a map, if there is one.
+    pub async fn read(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(path).await.ok()?).ok()
+    }
+
+    /// The original line of a 1-based line of the output.
+    pub fn line(&self, line: usize) -> Option<usize> {
+        self.lines
+            .get(line.checked_sub(1)?)
+            .copied()
+            .filter(|&l| l > 0)
+    }
+
+    /// Rewrite the `file:line:column` locations in `text` that point into
+    /// the preprocessed document at `output`, written as in `text`, to
+    /// point into the original.
+    ///
+    /// Locations in generated lines with no source are left alone.
+    pub fn rewrite(&self, output: &Path, text: &str) -> String {
+        text.split_inclusive(char::is_whitespace)
+            .map(|word| {
+                let token = word.trim_end();
+                self.location(output, token)
+                    .map_or_else(|| word.to_string(), |l| l + &word[token.len()..])
+            })
+            .collect()
+    }
+
+    /// The files that `file:line:column` locations in `text` point into,
+    /// each once, as written.
+    pub fn files(text: &str) -> Vec<&str> {
+        let mut files = Vec::new();
+        for token in text.split_whitespace() {
+            if let Some((file, _, _)) = split_location(token) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        files
+    }
+
+    /// The original of a `file:line:column` location in the output.
+    fn location(&self, output: &Path, token: &str) -> Option<String> {
+        let (file, line, column) = split_location(token)?;
+        if Path::new(file) != output {
+            return None;
+        }
+        Some(format!(
+            "{}:{}:{}",
+            self.source.display(),
+            self.line(line)?,
+            column
+        ))
+    }
+}
+
+fn split_location(token: &str) -> Option<(&str, usize, usize)> {
+    let mut parts = token.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    Some((parts.next()?, line, column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let map = SourceMap {
+            source: PathBuf::from("doc.typ"),
+            lines: vec![0, 0, 1, 2, 2, 2, 3],
+        };
+        let output = Path::new("doc.out.typ");
+        assert_eq!(
+            map.rewrite(
+                output,
+                "error: unknown variable: x\n  ┌─ doc.out.typ:5:2\n  ┌─ doc.out.typ:7:1\n"
+            ),
+            "error: unknown variable: x\n  ┌─ doc.typ:2:2\n  ┌─ doc.typ:3:1\n"
+        );
+        // Prelude lines, lines past the end and other files are left alone.
+        for text in [
+            "┌─ doc.out.typ:1:1",
+            "┌─ doc.out.typ:9:1",
+            "┌─ other.typ:3:1",
+            "┌─ chapters/doc.out.typ:3:1",
+            "see doc.out.typ",
+        ] {
+            assert_eq!(map.rewrite(output, text), text);
+        }
+    }
+
+    #[test]
+    fn test_files() {
+        assert_eq!(
+            SourceMap::files(
+                "error: x\n  ┌─ doc.out.typ:5:2\n  ┌─ chapters/a.out.typ:3:1\n  ┌─ doc.out.typ:7:1\n"
+            ),
+            ["doc.out.typ", "chapters/a.out.typ"]
+        );
+    }
+}
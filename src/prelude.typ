@@ -1,8 +1,31 @@
-#let src(content) = {
-  block(
+#let src(
+  listing: false,
+  caption: none,
+  line-numbers: false,
+  highlight: (),
+  content,
+) = {
+  show raw.line: it => {
+    let line = if line-numbers {
+      box(width: 2em, align(right, text(fill: gray, str(it.number)))) + h(1em) + it
+    } else {
+      it
+    }
+    if it.number in highlight {
+      box(fill: yellow.lighten(60%), outset: (y: 0.25em), line)
+    } else {
+      line
+    }
+  }
+  let body = block(
     fill: rgb("#ececec"), 
     inset: 1em,
     breakable: true)[
       #content
   ]
+  if listing or caption != none {
+    figure(kind: raw, caption: caption, body)
+  } else {
+    body
+  }
 }
@@ -1,26 +1,174 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
+use std::{fmt::Display, path::PathBuf};
 
+use serde::Serialize;
+pub use typstpp_backend::GraphicsType;
+use typstpp_backend::{Figure, Graphics, OptionValue, OutputType};
+
+use crate::{CodeOutput, Diagnostic};
+
+/// Where a chunk was found in the input, with 1-based lines and columns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub file: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub column: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.file.display(),
+            self.start_line,
+            self.column
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct SourceChunk {
+    pub chunk: Chunk,
+    pub span: Span,
+    /// The whitespace before a code block that stands on lines of its own,
+    /// so that its output can be indented the same way.
+    #[serde(skip)]
+    pub indent: String,
+}
+
+/// A chunk of the document along with what running it produced; chunks
+/// that are not run have no outputs.
+pub struct ExecutedChunk {
+    pub source: SourceChunk,
+    pub result: CodeOutput<String>,
+}
+
+impl ExecutedChunk {
+    /// Whether errors from the chunk are expected, as it is marked
+    /// `#| error: true`.
+    pub fn allowed(&self) -> bool {
+        self.source
+            .chunk
+            .code()
+            .and_then(|c| c.option("error"))
+            .and_then(|v| v.as_bool())
+            == Some(true)
+    }
+
+    /// An error of this chunk.
+    pub fn diagnostic(&self, message: &str) -> Diagnostic {
+        Diagnostic {
+            span: self.source.span.clone(),
+            message: message.trim_end().to_string(),
+            allowed: self.allowed(),
+        }
+    }
+
+    /// The errors of this chunk, whether the backend failed or the code
+    /// raised them.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let errors = self.result.errors.iter().map(String::as_str);
+        let outputs = self.result.outputs.iter().filter_map(|o| match o.ty {
+            OutputType::Error => Some(o.data.as_str()),
+            _ => None,
+        });
+        errors.chain(outputs).map(|e| self.diagnostic(e)).collect()
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Chunk {
     Verbatim(String),
     Code(CodeChunk),
+    Inline(CodeChunk),
     Output(typstpp_backend::Output<String>),
     Message(String),
     Error(String),
     Graphics(GraphicsChunk),
+    /// The path of a local Typst file that is included or imported.
+    Include(IncludeChunk),
+}
+
+impl Chunk {
+    /// The code of an executable chunk, whether it is a block or inline.
+    pub fn code(&self) -> Option<&CodeChunk> {
+        match self {
+            Chunk::Code(c) | Chunk::Inline(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Chunk::Inline(_))
+    }
 }
 
+#[derive(Clone, Serialize)]
 pub struct CodeChunk {
     pub lang: String,
-    pub options: HashMap<String, String>,
+    pub options: Vec<RawOption>,
     pub code: String,
 }
 
+impl CodeChunk {
+    /// The value of an option, if it is set and well-formed.
+    pub fn option(&self, name: &str) -> Option<OptionValue> {
+        self.options
+            .iter()
+            .rev()
+            .find(|o| o.name == name)
+            .and_then(|o| OptionValue::parse(&o.value).ok())
+    }
+
+    /// The files the chunk declares it reads with its `depends` option.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        let depends = match self.option("depends") {
+            Some(OptionValue::List(l)) => l,
+            Some(v) => vec![v],
+            None => vec![],
+        };
+        depends
+            .into_iter()
+            .filter_map(|v| v.as_str().map(PathBuf::from))
+            .collect()
+    }
+}
+
+/// A `#| name: value` line as written, before it is checked against the
+/// options the chunk's backend understands.
+#[derive(Debug, Clone, Serialize)]
+pub struct RawOption {
+    pub name: String,
+    pub value: String,
+    /// The whole line, for error messages.
+    pub line: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct IncludeChunk {
+    /// The path as written.
+    pub path: String,
+    /// The string literal giving the path, as it appears in the document.
+    pub literal: String,
+}
+
+#[derive(Clone, Serialize)]
 pub struct GraphicsChunk {
+    #[serde(skip)]
     pub data: Vec<u8>,
     pub ty: GraphicsType,
+    pub figure: Figure,
 }
 
-pub enum GraphicsType {
-    Png,
+impl From<Graphics> for GraphicsChunk {
+    fn from(g: Graphics) -> Self {
+        GraphicsChunk {
+            data: g.data,
+            ty: g.ty,
+            figure: g.figure,
+        }
+    }
 }